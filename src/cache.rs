@@ -0,0 +1,602 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::symlink;
+
+use anyhow::anyhow;
+use serde_derive::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+/// What a cache entry was built for, kept next to it as `<hash>.yml`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Metadata {
+    pub target: String,
+    pub language: String,
+    pub deps: Vec<String>,
+    /// Seconds since the epoch.
+    pub created: u64,
+    /// Seconds since the epoch that the entry was last stored or restored.
+    pub last_used: u64,
+    /// For repo builds, the hash of how the repo was built apart from its
+    /// commit, to check a build found without resolving `ref` against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(target: String, language: String, deps: Vec<String>) -> Metadata {
+        let now = now();
+        Metadata {
+            target,
+            language,
+            deps,
+            created: now,
+            last_used: now,
+            settings: None,
+        }
+    }
+}
+
+/// A stored build found in `build_dir`.
+pub struct Entry {
+    pub hash: String,
+    pub metadata: Option<Metadata>,
+    pub size: u64,
+}
+
+impl Entry {
+    /// When the entry was last used, falling back to when its folder last
+    /// changed for entries stored before metadata was written.
+    fn last_used(&self, build_dir: &Path) -> u64 {
+        match &self.metadata {
+            Some(metadata) => metadata.last_used,
+            None => fs::metadata(entry_path(build_dir, &self.hash))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|age| age.as_secs())
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// The folder holding the stored build for `hash` inside of `build_dir`.
+pub fn entry_path(build_dir: &Path, hash: &str) -> PathBuf {
+    build_dir.join(hash)
+}
+
+fn metadata_path(build_dir: &Path, hash: &str) -> PathBuf {
+    build_dir.join(format!("{}.yml", hash))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|age| age.as_secs())
+        .unwrap_or(0)
+}
+
+/// Write the metadata for `hash`.  An entry stored again keeps when it was
+/// first created.
+pub fn write_metadata(build_dir: &Path, hash: &str, metadata: &Metadata) -> anyhow::Result<()> {
+    let path = metadata_path(build_dir, hash);
+    let metadata = Metadata {
+        created: read_metadata(build_dir, hash)
+            .map(|existing| existing.created)
+            .unwrap_or(metadata.created),
+        ..metadata.clone()
+    };
+    fs::write(&path, serde_yaml::to_string(&metadata)?)
+        .map_err(|err| anyhow!("Failed to write {:?}: {}", path, err))
+}
+
+pub fn read_metadata(build_dir: &Path, hash: &str) -> Option<Metadata> {
+    let metadata = fs::read_to_string(metadata_path(build_dir, hash)).ok()?;
+    serde_yaml::from_str(&metadata).ok()
+}
+
+/// Record that the entry for `hash` was just used, so pruning keeps it.
+pub fn touch(build_dir: &Path, hash: &str) -> anyhow::Result<()> {
+    match read_metadata(build_dir, hash) {
+        Some(mut metadata) => {
+            metadata.last_used = now();
+            write_metadata(build_dir, hash, &metadata)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Where the lockfile resolved for `hash` is kept, next to its build.
+pub fn lockfile_path(build_dir: &Path, hash: &str) -> PathBuf {
+    build_dir.join(format!("{}.lock", hash))
+}
+
+/// Keep the lockfile an install produced.  A missing lockfile isn't an
+/// error, since not every install writes one.
+pub fn store_lockfile(lockfile: &Path, build_dir: &Path, hash: &str) -> anyhow::Result<()> {
+    if !lockfile.is_file() {
+        return Ok(());
+    }
+    fs::create_dir_all(build_dir)
+        .map_err(|err| anyhow!("Failed to create build directory: {}", err))?;
+    fs::copy(lockfile, lockfile_path(build_dir, hash))
+        .map_err(|err| anyhow!("Failed to store {:?}: {}", lockfile, err))?;
+    Ok(())
+}
+
+/// Put the stored lockfile for `hash` at `lockfile`, returning whether there
+/// was one.
+pub fn restore_lockfile(build_dir: &Path, hash: &str, lockfile: &Path) -> anyhow::Result<bool> {
+    let stored = lockfile_path(build_dir, hash);
+    if !stored.is_file() {
+        return Ok(false);
+    }
+    fs::copy(&stored, lockfile)
+        .map_err(|err| anyhow!("Failed to restore {:?}: {}", lockfile, err))?;
+    Ok(true)
+}
+
+/// Copy a fully built project into the cache.  The build is staged next to
+/// the final entry and then renamed into place, so a half-written copy is
+/// never picked up as a cache hit.
+pub fn store(project_path: &Path, build_dir: &Path, hash: &str) -> anyhow::Result<()> {
+    let entry = entry_path(build_dir, hash);
+    let staging = build_dir.join(format!("{}.partial", hash));
+
+    fs::create_dir_all(build_dir)
+        .map_err(|err| anyhow!("Failed to create build directory: {}", err))?;
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    copy_dir(project_path, &staging)?;
+
+    if entry.exists() {
+        fs::remove_dir_all(&entry)
+            .map_err(|err| anyhow!("Failed to remove stale cache entry: {}", err))?;
+    }
+    fs::rename(&staging, &entry)
+        .map_err(|err| anyhow!("Failed to move build into cache: {}", err))?;
+
+    Ok(())
+}
+
+/// Copy a stored build back out into the project folder.
+pub fn restore(build_dir: &Path, hash: &str, project_path: &Path) -> anyhow::Result<()> {
+    let entry = entry_path(build_dir, hash);
+    copy_dir(&entry, project_path)
+        .map_err(|err| anyhow!("Failed to restore cached build {}: {}", hash, err))
+}
+
+/// Recursively copy `from` into `to`.  Symlinks are recreated rather than
+/// followed, since package managers use relative links (`node_modules/.bin`)
+/// that would otherwise be turned into copies of their targets.
+pub fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    for entry in WalkDir::new(from) {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(from)?;
+        let destination = to.join(relative);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            fs::create_dir_all(&destination)?;
+        } else if file_type.is_symlink() {
+            let link = fs::read_link(entry.path())?;
+            if fs::symlink_metadata(&destination).is_ok() {
+                fs::remove_file(&destination)?;
+            }
+            symlink(link, &destination)?;
+        } else {
+            fs::copy(entry.path(), &destination).map_err(|err| {
+                anyhow!(
+                    "Failed to copy {:?} to {:?}: {}",
+                    entry.path(),
+                    destination,
+                    err
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stands in for the folder a build was installed in, in the files of a
+/// cache entry that hard-code it.
+pub const PROJECT_PATH: &str = "@shelly-project-path@";
+
+/// Replace `from` with `to` in every text file under `dir`, for tools like
+/// venvs that write the absolute path they were installed at into their
+/// scripts.  Binary files and symlinks are left alone.
+pub fn relocate(dir: &Path, from: &str, to: &str) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let contents = match fs::read_to_string(entry.path()) {
+            Ok(contents) if contents.contains(from) => contents,
+            _ => continue,
+        };
+        fs::write(entry.path(), contents.replace(from, to))
+            .map_err(|err| anyhow!("Failed to relocate {:?}: {}", entry.path(), err))?;
+    }
+
+    Ok(())
+}
+
+/// Hashes are upper-case hex SHA-256, which tells entries apart from the
+/// `.partial`, `.locking` and `.building` folders next to them.
+fn is_hash(name: &str) -> bool {
+    name.len() == 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+}
+
+/// Every stored build in `build_dir`, least recently used first.
+pub fn entries(build_dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    if !build_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(build_dir)? {
+        let dir_entry = dir_entry?;
+        let hash = dir_entry.file_name().to_string_lossy().to_string();
+        if !dir_entry.file_type()?.is_dir() || !is_hash(&hash) {
+            continue;
+        }
+        entries.push(Entry {
+            metadata: read_metadata(build_dir, &hash),
+            size: dir_size(&dir_entry.path()),
+            hash,
+        });
+    }
+    entries.sort_by_key(|entry| entry.last_used(build_dir));
+
+    Ok(entries)
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.path().symlink_metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Remove the stored build for `hash`, with its lockfile, metadata and any
+/// `<target>.sha1` pointing at it.
+pub fn remove(build_dir: &Path, hash: &str) -> anyhow::Result<()> {
+    fs::remove_dir_all(entry_path(build_dir, hash))
+        .map_err(|err| anyhow!("Failed to remove cached build {}: {}", hash, err))?;
+    for path in [
+        lockfile_path(build_dir, hash),
+        metadata_path(build_dir, hash),
+    ] {
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    for dir_entry in fs::read_dir(build_dir)? {
+        let path = dir_entry?.path();
+        let is_hash_file = path
+            .extension()
+            .is_some_and(|extension| extension == "sha1");
+        if is_hash_file && fs::read_to_string(&path).is_ok_and(|latest| latest.trim() == hash) {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print every stored build, most recently used first.
+pub fn list(build_dir: &Path) -> anyhow::Result<()> {
+    let entries = entries(build_dir)?;
+    if entries.is_empty() {
+        println!("No cached builds in {:?}", build_dir);
+        return Ok(());
+    }
+
+    let rows: Vec<[String; 6]> = entries
+        .iter()
+        .rev()
+        .map(|entry| {
+            let (target, language, deps) = match &entry.metadata {
+                Some(metadata) => (
+                    metadata.target.clone(),
+                    metadata.language.clone(),
+                    metadata.deps.join(", "),
+                ),
+                None => (String::from("?"), String::from("?"), String::new()),
+            };
+            [
+                target,
+                entry.hash[..12].to_string(),
+                language,
+                format_size(entry.size),
+                format!(
+                    "{} ago",
+                    format_age(now().saturating_sub(entry.last_used(build_dir)))
+                ),
+                deps,
+            ]
+        })
+        .collect();
+
+    let header = ["TARGET", "HASH", "LANGUAGE", "SIZE", "LAST USED", "DEPS"].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:width$}", column, width = width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Remove the entries built for the target named `name`, or the one whose
+/// hash starts with it.
+pub fn remove_matching(build_dir: &Path, name: &str) -> anyhow::Result<()> {
+    let entries = entries(build_dir)?;
+    let by_target: Vec<&Entry> = entries
+        .iter()
+        .filter(|entry| {
+            entry
+                .metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.target == name)
+        })
+        .collect();
+    let matching = if !by_target.is_empty() {
+        by_target
+    } else {
+        let by_hash: Vec<&Entry> = entries
+            .iter()
+            .filter(|entry| entry.hash.starts_with(&name.to_uppercase()))
+            .collect();
+        if by_hash.len() > 1 {
+            return Err(anyhow!("{} matches more than one cached build", name));
+        }
+        by_hash
+    };
+    if matching.is_empty() {
+        return Err(anyhow!("No cached build for {}", name));
+    }
+
+    for entry in matching {
+        remove(build_dir, &entry.hash)?;
+        println!("Removed {}", entry.hash);
+    }
+    Ok(())
+}
+
+/// Remove entries unused for longer than `older_than`, then the least
+/// recently used ones until the cache fits in `max_size` bytes.
+pub fn prune(
+    build_dir: &Path,
+    older_than: Option<Duration>,
+    max_size: Option<u64>,
+) -> anyhow::Result<()> {
+    if older_than.is_none() && max_size.is_none() {
+        return Err(anyhow!("Give --older-than, --max-size or both to prune by"));
+    }
+
+    let now = now();
+    let mut total: u64 = 0;
+    let mut kept = Vec::new();
+    let mut freed: u64 = 0;
+    for entry in entries(build_dir)? {
+        let age = now.saturating_sub(entry.last_used(build_dir));
+        if older_than.is_some_and(|older_than| age > older_than.as_secs()) {
+            remove(build_dir, &entry.hash)?;
+            freed += entry.size;
+            println!("Removed {} (unused for {})", entry.hash, format_age(age));
+        } else {
+            total += entry.size;
+            kept.push(entry);
+        }
+    }
+
+    if let Some(max_size) = max_size {
+        // Least recently used first.
+        for entry in kept {
+            if total <= max_size {
+                break;
+            }
+            remove(build_dir, &entry.hash)?;
+            total -= entry.size;
+            freed += entry.size;
+            println!("Removed {} ({})", entry.hash, format_size(entry.size));
+        }
+    }
+
+    println!("Freed {}, {} left", format_size(freed), format_size(total));
+    Ok(())
+}
+
+/// Remove every stored build, and everything kept alongside them, including
+/// the mirrors of `Repo` targets.
+pub fn clear(build_dir: &Path) -> anyhow::Result<()> {
+    if !build_dir.is_dir() {
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    for dir_entry in fs::read_dir(build_dir)? {
+        let path = dir_entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let stem = name.split('.').next().unwrap_or_default();
+
+        if path.is_dir() && is_hash(stem) {
+            fs::remove_dir_all(&path)?;
+            if is_hash(&name) {
+                removed += 1;
+            }
+        } else if is_hash(stem) || name.ends_with(".sha1") {
+            fs::remove_file(&path)?;
+        }
+    }
+
+    let mirrors = build_dir.join("mirrors");
+    if mirrors.is_dir() {
+        fs::remove_dir_all(&mirrors)?;
+        println!("Removed mirrored repos");
+    }
+
+    println!("Removed {} cached builds", removed);
+    Ok(())
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, units[unit])
+    }
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::temp_dir;
+
+    fn build_dir(name: &str) -> PathBuf {
+        temp_dir(&format!("cache-{}", name))
+    }
+
+    fn hash(c: char) -> String {
+        c.to_string().repeat(64)
+    }
+
+    /// Store an entry of `size` bytes last used `age` seconds ago.
+    fn add_entry(build_dir: &Path, hash: &str, size: usize, age: u64) {
+        fs::create_dir_all(entry_path(build_dir, hash)).unwrap();
+        fs::write(entry_path(build_dir, hash).join("file"), vec![0; size]).unwrap();
+        let mut metadata = Metadata::new(hash[..1].to_string(), String::from("node"), vec![]);
+        metadata.last_used -= age;
+        write_metadata(build_dir, hash, &metadata).unwrap();
+    }
+
+    fn hashes(build_dir: &Path) -> Vec<String> {
+        entries(build_dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.hash)
+            .collect()
+    }
+
+    #[test]
+    fn restores_what_was_stored() {
+        let dir = build_dir("round-trip");
+        let project = dir.join("project");
+        fs::create_dir_all(project.join("node_modules/pkg")).unwrap();
+        fs::write(project.join("package.json"), "{}").unwrap();
+        fs::write(project.join("node_modules/pkg/index.js"), "pkg").unwrap();
+        symlink("../pkg/index.js", project.join("node_modules/pkg/bin")).unwrap();
+        store(&project, &dir, &hash('A')).unwrap();
+        assert!(!dir.join(format!("{}.partial", hash('A'))).exists());
+
+        let restored = dir.join("restored");
+        fs::create_dir(&restored).unwrap();
+        fs::write(restored.join("package.json"), "old").unwrap();
+        restore(&dir, &hash('A'), &restored).unwrap();
+        assert_eq!(
+            fs::read_to_string(restored.join("package.json")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            fs::read_to_string(restored.join("node_modules/pkg/index.js")).unwrap(),
+            "pkg"
+        );
+        assert_eq!(
+            fs::read_link(restored.join("node_modules/pkg/bin")).unwrap(),
+            Path::new("../pkg/index.js")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_hex_names_are_hashes() {
+        assert!(is_hash(&hash('A')));
+        assert!(is_hash(&"0123456789ABCDEF".repeat(4)));
+        assert!(!is_hash(&hash('G')));
+        assert!(!is_hash(&hash('a')));
+        assert!(!is_hash(&format!("{}.partial", hash('A'))));
+    }
+
+    #[test]
+    fn prunes_old_then_least_recently_used() {
+        let dir = build_dir("prune");
+        add_entry(&dir, &hash('A'), 100, 10);
+        add_entry(&dir, &hash('B'), 100, 40 * 24 * 60 * 60);
+        add_entry(&dir, &hash('C'), 100, 20);
+        add_entry(&dir, &hash('D'), 100, 30);
+        assert_eq!(hashes(&dir), [hash('B'), hash('D'), hash('C'), hash('A')]);
+
+        prune(&dir, Some(Duration::from_secs(30 * 24 * 60 * 60)), None).unwrap();
+        assert_eq!(hashes(&dir), [hash('D'), hash('C'), hash('A')]);
+
+        prune(&dir, None, Some(250)).unwrap();
+        assert_eq!(hashes(&dir), [hash('C'), hash('A')]);
+        assert!(!metadata_path(&dir, &hash('D')).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_created_when_stored_again() {
+        let dir = build_dir("created");
+        let mut metadata = Metadata::new(String::from("node"), String::from("node"), vec![]);
+        metadata.created -= 100;
+        write_metadata(&dir, &hash('A'), &metadata).unwrap();
+        let created = metadata.created;
+
+        write_metadata(
+            &dir,
+            &hash('A'),
+            &Metadata::new(String::from("node"), String::from("node"), vec![]),
+        )
+        .unwrap();
+        assert_eq!(read_metadata(&dir, &hash('A')).unwrap().created, created);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
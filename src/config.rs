@@ -1,9 +1,15 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
 
+use anyhow::anyhow;
 use serde_derive::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Sequence, Value};
 
+use crate::opts::parse_duration;
 use crate::target::{
-    DefaultTarget, Dependency, ProgramCommand, RemoteTarget, SupportedLanguage, Target,
+    include_deps, DefaultTarget, Dependencies, Dependency, ProgramCommand, RemoteTarget,
+    SupportedLanguage, Target,
 };
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -11,7 +17,203 @@ pub struct Config {
     pub build_dir: String,
     pub cache: Option<bool>,
     pub default_target: String,
+    /// Named lists of dependencies that targets can `include`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub dep_sets: HashMap<String, Dependencies>,
+    /// How long a `Repo` target's mirror is used before fetching into it
+    /// again, like `30m` or `1d`.  Defaults to an hour.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror_ttl: Option<String>,
     pub targets: HashMap<String, Target>,
+    /// Targets whose `extends` couldn't be followed, with why, so that only
+    /// using one of them fails.
+    #[serde(skip)]
+    pub broken: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn mirror_ttl(&self) -> anyhow::Result<Duration> {
+        match &self.mirror_ttl {
+            Some(ttl) => parse_duration(ttl).map_err(|err| anyhow!("Invalid mirror_ttl: {}", err)),
+            None => Ok(Duration::from_secs(60 * 60)),
+        }
+    }
+
+    /// The target called `name`, with the dependency sets it includes
+    /// merged into its own dependencies.
+    pub fn target(&self, name: &str) -> anyhow::Result<Target> {
+        if let Some(err) = self.broken.get(name) {
+            return Err(anyhow!("{}", err));
+        }
+        let mut target = self
+            .targets
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow!("Target {} not specified in `shelly.yml` file", name))?;
+
+        let (include, deps, language) = match &mut target {
+            Target::Internal(t) => (&mut t.include, &mut t.deps, t.language.clone()),
+            Target::Custom(t) => (&mut t.include, &mut t.deps, None),
+            Target::Directory(_) | Target::Repo(_) => return Ok(target),
+        };
+        if !include.is_empty() {
+            *deps = Some(include_deps(
+                &self.dep_sets,
+                include,
+                deps.take().unwrap_or_default(),
+                |name| match &language {
+                    Some(language) => language.normalize_name(name),
+                    None => name.to_string(),
+                },
+            )?);
+            include.clear();
+        }
+
+        Ok(target)
+    }
+}
+
+/// Shelly's own config folder, `~/.config/shelly`, where the default
+/// `shelly.yml` and the user's templates live.
+pub fn home_dir() -> anyhow::Result<PathBuf> {
+    let home = std::env::var("HOME").map_err(|err| anyhow!("Failed to read $HOME: {}", err))?;
+    Ok(PathBuf::from(home).join(".config").join("shelly"))
+}
+
+/// Lists in a target that `extends` another whose entries replace the
+/// parent's by the given field, instead of replacing the whole list.
+const KEYED_LISTS: [(&str, &str); 3] = [
+    ("deps", "name"),
+    ("extra_files", "path"),
+    ("source_templates", "path"),
+];
+
+/// Maps in a target that `extends` another that add to the parent's.
+const MERGED_MAPS: [&str; 2] = ["vars", "prompts"];
+
+/// Parse `shelly.yml`, filling in each target that `extends` another with
+/// the fields of its parent.  Targets whose `extends` can't be followed are
+/// left out of `targets` and kept in `broken` instead.
+pub fn parse(config_file: &str) -> anyhow::Result<Config> {
+    let mut config: Value = serde_yaml::from_str(config_file)
+        .map_err(|err| anyhow!("Error parsing config file: {}", err))?;
+
+    let mut broken = HashMap::new();
+    if let Some(targets) = config.get_mut("targets").and_then(Value::as_mapping_mut) {
+        let declared = targets.clone();
+        for (name, target) in targets.iter_mut() {
+            if let Some(name) = name.as_str() {
+                match resolve_extends(&declared, name, &mut Vec::new()) {
+                    Ok(resolved) => *target = resolved,
+                    Err(err) => {
+                        broken.insert(name.to_string(), err.to_string());
+                    }
+                }
+            }
+        }
+        for name in broken.keys() {
+            targets.remove(&Value::from(name.as_str()));
+        }
+    }
+
+    let mut config: Config = serde_yaml::from_value(config)
+        .map_err(|err| anyhow!("Error parsing config file: {}", err))?;
+    config.broken = broken;
+    Ok(config)
+}
+
+fn resolve_extends(
+    targets: &Mapping,
+    name: &str,
+    chain: &mut Vec<String>,
+) -> anyhow::Result<Value> {
+    if chain.iter().any(|seen| seen == name) {
+        chain.push(name.to_string());
+        return Err(anyhow!(
+            "Targets extend each other in a cycle: {}",
+            chain.join(" -> ")
+        ));
+    }
+    let target = targets
+        .get(&Value::from(name))
+        .ok_or_else(|| match chain.last() {
+            Some(child) => anyhow!("Target {} extends {}, which doesn't exist", child, name),
+            None => anyhow!("Target {} not specified in `shelly.yml` file", name),
+        })?;
+
+    // A target is a single entry, from its kind to its fields.
+    let (kind, fields) = match target.as_mapping().and_then(|target| target.iter().next()) {
+        Some((kind, Value::Mapping(fields))) => (kind, fields),
+        _ => return Ok(target.clone()),
+    };
+    let mut fields = fields.clone();
+    let parent_name = match fields.remove(&Value::from("extends")) {
+        Some(Value::String(parent_name)) => parent_name,
+        Some(_) => return Err(anyhow!("Target {} must extend a target by name", name)),
+        None => return Ok(target.clone()),
+    };
+
+    chain.push(name.to_string());
+    let parent = resolve_extends(targets, &parent_name, chain)?;
+    let parent_fields = parent
+        .get(kind)
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| {
+            anyhow!(
+                "Target {} can't extend {}, which is not a {} target",
+                name,
+                parent_name,
+                kind.as_str().unwrap_or_default()
+            )
+        })?;
+
+    let mut resolved = Mapping::new();
+    resolved.insert(
+        kind.clone(),
+        Value::Mapping(merge_fields(parent_fields, fields)),
+    );
+    Ok(Value::Mapping(resolved))
+}
+
+fn merge_fields(parent: &Mapping, child: Mapping) -> Mapping {
+    let mut merged = parent.clone();
+    for (key, value) in child {
+        let field = key.as_str().unwrap_or_default();
+        let value = match (merged.get(&key), value) {
+            (Some(Value::Sequence(inherited)), Value::Sequence(own)) => {
+                match KEYED_LISTS.iter().find(|(list, _)| *list == field) {
+                    Some((_, id)) => Value::Sequence(merge_list(inherited, own, id)),
+                    None => Value::Sequence(own),
+                }
+            }
+            (Some(Value::Mapping(inherited)), Value::Mapping(own))
+                if MERGED_MAPS.contains(&field) =>
+            {
+                let mut map = inherited.clone();
+                for (name, value) in own {
+                    map.insert(name, value);
+                }
+                Value::Mapping(map)
+            }
+            (_, value) => value,
+        };
+        merged.insert(key, value);
+    }
+    merged
+}
+
+fn merge_list(inherited: &[Value], own: Sequence, id: &str) -> Sequence {
+    let mut merged = inherited.to_vec();
+    for entry in own {
+        let existing = merged
+            .iter_mut()
+            .find(|existing| existing.get(id).is_some() && existing.get(id) == entry.get(id));
+        match existing {
+            Some(existing) => *existing = entry,
+            None => merged.push(entry),
+        }
+    }
+    merged
 }
 
 pub fn default() -> Config {
@@ -41,9 +243,31 @@ pub fn default() -> Config {
         Target::Internal(node_target),
     );
 
+    let python_deps = vec![
+        Dependency::new("requests", "2.25"),
+        Dependency::new("beautifulsoup4", "4.9"),
+    ];
+    let python_target =
+        DefaultTarget::new("python".to_string(), SupportedLanguage::python, python_deps);
+    targets.insert(
+        SupportedLanguage::python.to_string(),
+        Target::Internal(python_target),
+    );
+
+    let ruby_deps = vec![
+        Dependency::new("csv", "3.1"),
+        Dependency::new("httparty", "0.18"),
+        Dependency::new("nokogiri", "1.11"),
+    ];
+    let ruby_target = DefaultTarget::new("ruby".to_string(), SupportedLanguage::ruby, ruby_deps);
+    targets.insert(
+        SupportedLanguage::ruby.to_string(),
+        Target::Internal(ruby_target),
+    );
+
     let rust_deps = vec![
         Dependency::new("clap", "1.0"),
-        Dependency::new("tokio", "0.9"),
+        Dependency::new("tokio", "1.0"),
     ];
     let rust_target = DefaultTarget::new("rust".to_string(), SupportedLanguage::rust, rust_deps);
     targets.insert(
@@ -75,6 +299,172 @@ pub fn default() -> Config {
         build_dir: String::from("/tmp/shelly"),
         cache: Some(true),
         default_target: String::from("elixir"),
+        dep_sets: HashMap::new(),
+        mirror_ttl: None,
         targets,
+        broken: HashMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::target::GitMode;
+
+    const CONFIG: &str = "
+build_dir: /tmp/shelly
+default_target: base
+targets:
+  base:
+    Internal:
+      name: base
+      language: node
+      deps:
+        - name: axios
+          version: '0.20'
+        - name: cheerio
+          version: '1.0'
+      extra_files:
+        - path: a.txt
+          content: a
+        - path: b.txt
+          content: b
+      vars:
+        app: base
+        author: me
+      prompts:
+        app:
+          description: The app
+      git: none
+  child:
+    Internal:
+      extends: base
+      name: child
+      deps:
+        - name: axios
+          version: '0.21'
+        - name: papaparse
+          version: '5.3'
+      extra_files:
+        - path: b.txt
+          content: child
+      vars:
+        app: child
+      prompts:
+        name:
+          description: The name
+  repo:
+    Repo:
+      path: https://example.com/repo.git
+      build_command: npm
+      build_args: [install]
+";
+
+    fn internal(config: &Config, name: &str) -> DefaultTarget {
+        match config.target(name).unwrap() {
+            Target::Internal(target) => target,
+            _ => panic!("{} isn't an Internal target", name),
+        }
+    }
+
+    #[test]
+    fn extends_merges_into_the_parent() {
+        let config = parse(CONFIG).unwrap();
+        let child = internal(&config, "child");
+
+        assert_eq!(child.name, "child");
+        assert!(matches!(child.language, Some(SupportedLanguage::node)));
+        assert!(matches!(child.git, Some(GitMode::none)));
+
+        let deps: Vec<(&str, Option<&str>)> = child
+            .deps
+            .iter()
+            .flatten()
+            .map(|dep| (dep.name.as_str(), dep.version.as_deref()))
+            .collect();
+        assert_eq!(
+            deps,
+            vec![
+                ("axios", Some("0.21")),
+                ("cheerio", Some("1.0")),
+                ("papaparse", Some("5.3"))
+            ]
+        );
+
+        let files: Vec<(&str, Option<&str>)> = child
+            .extra_files
+            .iter()
+            .map(|file| (file.path.as_str(), file.content.as_deref()))
+            .collect();
+        assert_eq!(files, vec![("a.txt", Some("a")), ("b.txt", Some("child"))]);
+
+        assert_eq!(child.vars["app"], "child");
+        assert_eq!(child.vars["author"], "me");
+        let mut prompts: Vec<&String> = child.prompts.keys().collect();
+        prompts.sort();
+        assert_eq!(prompts, vec!["app", "name"]);
+
+        // The parent is left as it was.
+        assert_eq!(internal(&config, "base").vars["app"], "base");
+    }
+
+    #[test]
+    fn cycles_only_break_their_targets() {
+        let config = parse(&format!(
+            "{}
+  a:
+    Internal:
+      extends: b
+      name: a
+  b:
+    Internal:
+      extends: a
+      name: b
+  c:
+    Internal:
+      extends: a
+      name: c
+",
+            CONFIG
+        ))
+        .unwrap();
+
+        let err = config.target("a").unwrap_err().to_string();
+        assert!(err.contains("a -> b -> a"), "{}", err);
+        let err = config.target("c").unwrap_err().to_string();
+        assert!(err.contains("c -> a -> b -> a"), "{}", err);
+        assert_eq!(internal(&config, "child").name, "child");
+    }
+
+    #[test]
+    fn extends_needs_the_same_kind() {
+        let config = parse(&format!(
+            "{}
+  mixed:
+    Internal:
+      extends: repo
+      name: mixed
+",
+            CONFIG
+        ))
+        .unwrap();
+
+        let err = config.target("mixed").unwrap_err().to_string();
+        assert!(
+            err.contains("can't extend repo, which is not a Internal target"),
+            "{}",
+            err
+        );
+        let err = parse(&CONFIG.replace("extends: base", "extends: missing"))
+            .unwrap()
+            .target("child")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("extends missing, which doesn't exist"),
+            "{}",
+            err
+        );
     }
 }
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::opts::Opts;
+use crate::target::{
+    generate_hash, merge_deps, stays_inside, Dependencies, GitMode, LanguageTarget,
+    LanguageTemplate, Lockfile, ProgramCommand, Shell,
+};
+use crate::templates::Prompt;
+
+/// A file to generate in the project, rendered from a template file on disk.
+/// Relative template paths are resolved from the folder holding `shelly.yml`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TemplateFile {
+    pub path: String,
+    pub template: String,
+}
+
+/// A language described entirely in `shelly.yml`, for anything without
+/// built-in support.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CustomTarget {
+    pub name: String,
+    pub build_template: TemplateFile,
+    #[serde(default)]
+    pub source_templates: Vec<TemplateFile>,
+    pub source_directory: String,
+    /// How to write a single dependency, with `{name}` and `{version}` in
+    /// place of the dependency's fields.
+    pub dep_format: String,
+    /// What to put between dependencies, defaulting to a newline.
+    pub dep_separator: Option<String>,
+    pub deps: Option<Dependencies>,
+    /// Names of `dep_sets` whose dependencies this target also uses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    pub install: ProgramCommand,
+    /// The file `install` records resolved versions in, to keep next to the
+    /// build cache and reuse.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lockfile: Option<String>,
+    /// How to write `lockfile` without installing, defaulting to `install`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock: Option<ProgramCommand>,
+    /// How to install using only local caches, for `--offline` runs without
+    /// a cached build.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_install: Option<ProgramCommand>,
+    pub shell: Option<ProgramCommand>,
+    /// Variables for the templates, available as `vars.<name>`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: HashMap<String, String>,
+    /// How to ask for variables the templates use but nothing has set.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub prompts: HashMap<String, Prompt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitMode>,
+}
+
+impl CustomTarget {
+    pub fn execute(
+        &self,
+        opts: Opts,
+        build_dir: String,
+        cache: bool,
+        config_dir: &Path,
+    ) -> anyhow::Result<()> {
+        let cache = cache && !opts.get_no_cache();
+        let target = self.prepare(&opts, opts.get_shell(), config_dir)?;
+        target.build(self.name.clone(), opts.get_path(), build_dir, cache)
+    }
+
+    /// Resolve the target's dependencies into a lockfile in `build_dir`.
+    pub fn lock(&self, opts: Opts, build_dir: String, config_dir: &Path) -> anyhow::Result<()> {
+        if self.lockfile.is_none() {
+            return Err(anyhow!(
+                "Custom target {} has no `lockfile` to resolve into",
+                self.name
+            ));
+        }
+        self.prepare(&opts, false, config_dir)?.lock(build_dir)
+    }
+
+    fn prepare(
+        &self,
+        opts: &Opts,
+        shell: bool,
+        config_dir: &Path,
+    ) -> anyhow::Result<LanguageTarget<Context>> {
+        let deps = merge_deps(
+            self.deps.clone().unwrap_or_default(),
+            opts.get_deps(),
+            opts.get_without(),
+            str::to_string,
+        )?;
+        let mut target = new(self, deps.clone(), shell, config_dir)?;
+        target.describe(String::from("custom"), &deps);
+        target.set_offline(opts.get_offline());
+        if let Some(command) = &self.offline_install {
+            target.set_offline_commands(vec![command.clone()]);
+        }
+        target.set_git(self.git.unwrap_or_default());
+        target.add_vars(
+            self.vars
+                .clone()
+                .into_iter()
+                .chain(opts.get_vars())
+                .collect(),
+        );
+        target.set_prompts(self.prompts.clone(), !opts.get_no_input());
+        target.set_refresh_lock(opts.get_refresh_lock());
+        if let Some(file) = &self.lockfile {
+            inside_project(file, "Lockfile")?;
+            let command = self.lock.clone().unwrap_or_else(|| self.install.clone());
+            target.set_lockfile(Lockfile::new(file.clone(), command));
+        }
+        Ok(target)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Context {
+    pub deps: Dependencies,
+    pub dep_string: String,
+    pub packages: Vec<String>,
+}
+
+pub fn new(
+    target: &CustomTarget,
+    deps: Dependencies,
+    shell: bool,
+    config_dir: &Path,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    inside_project(&target.source_directory, "Source directory")?;
+    let build_template = read_template(&target.build_template, config_dir)?;
+    let source_templates = target
+        .source_templates
+        .iter()
+        .map(|file| read_template(file, config_dir))
+        .collect::<anyhow::Result<Vec<LanguageTemplate>>>()?;
+
+    let shell = match (shell, &target.shell) {
+        (false, _) => None,
+        (true, None) => {
+            return Err(anyhow!(
+                "No shell command specified in config for {}",
+                target.name
+            ))
+        }
+        (true, Some(command)) => {
+            let script = generate_shell_script(command);
+            let command = command.clone();
+            Some(Shell::new(Box::new(move || Ok(command.clone())), script))
+        }
+    };
+
+    Ok(LanguageTarget::new(
+        build_template,
+        Context {
+            deps: deps.clone(),
+            dep_string: generate_dep_string(
+                deps.clone(),
+                &target.dep_format,
+                target.dep_separator.as_deref().unwrap_or("\n"),
+            )?,
+            packages: generate_packages(deps.clone()),
+        },
+        generate_hash(deps, format!("custom:{}", target.name)),
+        vec![target.install.clone()],
+        shell,
+        target.source_directory.clone(),
+        source_templates,
+    ))
+}
+
+/// Fail unless `path` is relative to the project and inside it.
+fn inside_project(path: &str, what: &str) -> anyhow::Result<()> {
+    if !stays_inside(Path::new(path)) {
+        return Err(anyhow!(
+            "{} path must be relative to the project and inside it, got {}",
+            what,
+            path
+        ));
+    }
+    Ok(())
+}
+
+fn read_template(file: &TemplateFile, config_dir: &Path) -> anyhow::Result<LanguageTemplate> {
+    inside_project(&file.path, "Template")?;
+    let template = fs::read_to_string(config_dir.join(&file.template))
+        .map_err(|err| anyhow!("Failed to read template {}: {}", file.template, err))?;
+    Ok((file.path.clone(), template))
+}
+
+fn generate_shell_script(command: &ProgramCommand) -> String {
+    let line = std::iter::once(command.command.clone())
+        .chain(
+            command
+                .args
+                .iter()
+                .map(|arg| format!("'{}'", arg.replace('\'', "'\\''"))),
+        )
+        .collect::<Vec<String>>()
+        .join(" ");
+    // The script is run through TinyTemplate like any other template.
+    format!("#!/bin/bash\n\n{}\n", line.replace('{', "\\{"))
+}
+
+fn generate_dep_string(
+    deps: Dependencies,
+    format: &str,
+    separator: &str,
+) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(|dep| {
+            // `dep_format` only has a name and a version to fill in.
+            dep.unsupported(
+                "custom",
+                &["git", "path", "registry", "organization", "only"],
+            )?;
+            // Custom languages get the version exactly as it was written.
+            let version = dep.requirement.as_ref().or(dep.version.as_ref());
+            Ok(format
+                .replace("{name}", &dep.name)
+                .replace("{version}", version.map(String::as_str).unwrap_or("")))
+        })
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join(separator))
+}
+
+fn generate_packages(deps: Dependencies) -> Vec<String> {
+    deps.iter().map(|dep| dep.name.clone()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use clap::Clap;
+    use tinytemplate::TinyTemplate;
+
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn shell_script_quotes_args() {
+        let command = ProgramCommand::new(
+            String::from("printf"),
+            vec![
+                "%s|".into(),
+                "it's".into(),
+                "{x}".into(),
+                "'; rm -rf x'".into(),
+            ],
+        );
+        let script = generate_shell_script(&command);
+        let mut templates = TinyTemplate::new();
+        templates.add_template("shell", &script).unwrap();
+        let rendered = templates.render("shell", &()).unwrap();
+        let output = std::process::Command::new("bash")
+            .args(["-c", &rendered])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "it's|{x}|'; rm -rf x'|"
+        );
+    }
+
+    #[test]
+    fn paths_stay_inside_the_project() {
+        let target = |source_directory: &str, lockfile: &str| -> CustomTarget {
+            serde_yaml::from_str(&format!(
+                "
+name: lang
+build_template:
+  path: deps.txt
+  template: deps.txt.tmpl
+source_directory: {}
+dep_format: '{{name}}'
+install:
+  command: 'true'
+  args: []
+lockfile: {}
+",
+                source_directory, lockfile
+            ))
+            .unwrap()
+        };
+        let config_dir = temp_dir("custom");
+        fs::write(config_dir.join("deps.txt.tmpl"), "{dep_string}").unwrap();
+        let prepare = |target: CustomTarget| {
+            let opts = Opts::parse_from(["shelly"]);
+            target.prepare(&opts, false, &config_dir).map(|_| ())
+        };
+
+        assert!(prepare(target("src", "deps.lock")).is_ok());
+        for (source_directory, lockfile) in [
+            ("../src", "deps.lock"),
+            ("/tmp/src", "deps.lock"),
+            ("src", "../deps.lock"),
+            ("src", "/tmp/deps.lock"),
+        ] {
+            let err = prepare(target(source_directory, lockfile)).unwrap_err();
+            assert!(err.to_string().contains("inside it"), "{}", err);
+        }
+        fs::remove_dir_all(config_dir).unwrap();
+    }
+}
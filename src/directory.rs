@@ -0,0 +1,668 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::symlink;
+
+use anyhow::anyhow;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use serde_derive::{Deserialize, Serialize};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::cache::format_size;
+
+/// Files in any folder of a `Directory` target listing paths not to copy, in
+/// `.gitignore` syntax.  Where they disagree, `.shellyignore` wins.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".shellyignore"];
+
+/// What's skipped when a target doesn't give its own `exclude`.
+pub const DEFAULT_EXCLUDE: [&str; 2] = [".git", "node_modules"];
+
+/// What to do with the symlinks in a `Directory` target.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum SymlinkMode {
+    /// Recreate the link, pointing wherever it pointed before.
+    #[default]
+    copy,
+    /// Copy whatever the link points at.
+    follow,
+    /// Leave the link out.
+    skip,
+}
+
+/// Decides which paths under a folder get copied.  `exclude` always wins,
+/// then `include`, then the ignore files closest to the path.  As with git,
+/// nothing inside a skipped folder is copied, unless it's included; skipped
+/// folders are only looked in when an `include` glob could match inside.
+pub struct Filter {
+    root: PathBuf,
+    exclude: Gitignore,
+    include: Gitignore,
+    include_globs: Vec<String>,
+    ignore_files: HashMap<PathBuf, Gitignore>,
+    /// Skipped folders being looked in for included paths.
+    searched: Vec<PathBuf>,
+}
+
+impl Filter {
+    pub fn new(root: &Path, exclude: &[String], include: &[String]) -> anyhow::Result<Filter> {
+        Ok(Filter {
+            root: root.to_path_buf(),
+            exclude: globs(root, exclude)?,
+            include: globs(root, include)?,
+            include_globs: include.to_vec(),
+            ignore_files: HashMap::new(),
+            searched: Vec::new(),
+        })
+    }
+
+    pub fn is_copied(&mut self, path: &Path, is_dir: bool) -> bool {
+        if path == self.root {
+            return true;
+        }
+        if self
+            .exclude
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return false;
+        }
+        if self
+            .include
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+        {
+            return true;
+        }
+        if self.searched.iter().any(|dir| path.starts_with(dir)) {
+            return false;
+        }
+
+        let root = self.root.clone();
+        for dir in path.ancestors().skip(1) {
+            match self.ignore_file(dir).matched(path, is_dir) {
+                Match::Ignore(_) => return false,
+                Match::Whitelist(_) => return true,
+                Match::None => {}
+            }
+            if dir == root {
+                break;
+            }
+        }
+        true
+    }
+
+    /// Whether any `include` glob could match a path inside `dir`.  Globs
+    /// without a `/` can match at any depth, while the rest are anchored to
+    /// the root and only match under folders their leading parts name.
+    fn may_include_beneath(&self, dir: &Path) -> bool {
+        let relative = match dir.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let is_glob = |part: &str| part.contains(['*', '?', '[']);
+
+        self.include_globs.iter().any(|glob| {
+            if glob.starts_with('!') {
+                return false;
+            }
+            let glob = glob.trim_end_matches('/');
+            if !glob.contains('/') || glob.starts_with("**/") {
+                return true;
+            }
+            let mut parts = glob.trim_start_matches('/').split('/');
+            for component in relative.components() {
+                match parts.next() {
+                    Some(part) if is_glob(part) => return true,
+                    Some(part) if component.as_os_str() == part => {}
+                    _ => return false,
+                }
+            }
+            parts.next().is_some()
+        })
+    }
+
+    /// The rules from the ignore files in `dir`, read the first time they're
+    /// needed.
+    fn ignore_file(&mut self, dir: &Path) -> &Gitignore {
+        self.ignore_files
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                let mut builder = GitignoreBuilder::new(dir);
+                for file in IGNORE_FILES.iter() {
+                    let path = dir.join(file);
+                    if path.is_file() {
+                        // Like git, lines that don't parse are skipped
+                        // rather than failing the whole file.
+                        let _ = builder.add(path);
+                    }
+                }
+                builder.build().unwrap_or_else(|_| Gitignore::empty())
+            })
+    }
+}
+
+/// A matcher for `.gitignore` style globs relative to `root`.
+fn globs(root: &Path, globs: &[String]) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+    for glob in globs {
+        builder
+            .add_line(None, glob)
+            .map_err(|err| anyhow!("Invalid glob {}: {}", glob, err))?;
+    }
+    builder
+        .build()
+        .map_err(|err| anyhow!("Failed to build globs: {}", err))
+}
+
+/// Everything under `root` that `filter` lets through, starting with `root`.
+pub fn entries(
+    root: &Path,
+    mut filter: Filter,
+    symlinks: SymlinkMode,
+) -> anyhow::Result<Vec<DirEntry>> {
+    let entries = WalkDir::new(root)
+        .follow_links(matches!(symlinks, SymlinkMode::follow))
+        .into_iter()
+        .filter_entry(|entry| {
+            let is_dir = entry.file_type().is_dir();
+            if matches!(symlinks, SymlinkMode::skip) && entry.path_is_symlink() {
+                return false;
+            }
+            if filter.is_copied(entry.path(), is_dir) {
+                return true;
+            }
+            let searched = is_dir
+                && !filter
+                    .exclude
+                    .matched_path_or_any_parents(entry.path(), true)
+                    .is_ignore()
+                && filter.may_include_beneath(entry.path());
+            if searched {
+                filter.searched.push(entry.path().to_path_buf());
+            }
+            searched
+        })
+        .map(|entry| entry.map_err(|err| anyhow!("Failed to read {:?}: {}", root, err)))
+        .collect::<anyhow::Result<Vec<DirEntry>>>()?;
+
+    // Skipped folders that were only looked in are kept as the parents of
+    // whatever was included inside them, and dropped if nothing was.
+    let searched = filter.searched;
+    let copied: Vec<PathBuf> = entries
+        .iter()
+        .filter(|entry| !searched.iter().any(|dir| dir == entry.path()))
+        .map(|entry| entry.path().to_path_buf())
+        .collect();
+    Ok(entries
+        .into_iter()
+        .filter(|entry| {
+            !searched.iter().any(|dir| dir == entry.path())
+                || copied.iter().any(|path| path.starts_with(entry.path()))
+        })
+        .collect())
+}
+
+/// What a copy wrote, for its summary.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files: usize,
+    pub links: usize,
+    pub bytes: u64,
+}
+
+/// Fail if anything called one of `names` is already in the folder `to`,
+/// rather than overwrite or merge into it.
+pub fn check_conflicts<I>(names: I, to: &Path) -> anyhow::Result<()>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut conflicts: Vec<String> = names
+        .into_iter()
+        .filter(|name| fs::symlink_metadata(to.join(name)).is_ok())
+        .map(|name| name.to_string_lossy().to_string())
+        .collect();
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    conflicts.sort();
+    conflicts.dedup();
+    Err(anyhow!("{:?} already has {}", to, conflicts.join(", ")))
+}
+
+/// Move everything inside `from` into the folder `to`, for when `to` already
+/// exists and so can't be renamed over.  Nothing is moved if anything in
+/// `to` would be replaced.
+pub fn move_contents(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let names = fs::read_dir(from)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<OsString>>>()?;
+    check_conflicts(names.iter().cloned(), to)?;
+    for name in names {
+        fs::rename(from.join(&name), to.join(&name))
+            .map_err(|err| anyhow!("Failed to move {:?} into {:?}: {}", name, to, err))?;
+    }
+    Ok(())
+}
+
+/// Copy `entries`, as found under `root` by [`entries`], into `destination`,
+/// keeping permissions and modification times.  Files are copied in
+/// parallel into a folder next to `destination` that is only renamed into
+/// place once everything's there, so a failed copy leaves nothing behind.
+/// A `destination` that already exists, like `.`, has the copy staged inside
+/// it instead and moved out of there, and fails up front if it already has
+/// anything the copy would replace.
+pub fn copy(root: &Path, entries: &[DirEntry], destination: &Path) -> anyhow::Result<Summary> {
+    let existing = destination.exists();
+    let staging = if existing {
+        let top_level = entries.iter().filter_map(|entry| {
+            entry
+                .path()
+                .strip_prefix(root)
+                .ok()
+                .and_then(|path| path.components().next())
+                .map(|name| name.as_os_str().to_os_string())
+        });
+        check_conflicts(top_level, destination)?;
+        fs::canonicalize(destination)?.join(".shelly-partial")
+    } else {
+        let name = destination
+            .file_name()
+            .ok_or_else(|| anyhow!("Can't copy into {:?}", destination))?;
+        destination.with_file_name(format!("{}.partial", name.to_string_lossy()))
+    };
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    let copied = stage(root, entries, &staging).and_then(|summary| {
+        if existing {
+            move_contents(&staging, destination)?;
+            fs::remove_dir(&staging)?;
+        } else {
+            fs::rename(&staging, destination)
+                .map_err(|err| anyhow!("Failed to move copy into {:?}: {}", destination, err))?;
+        }
+        Ok(summary)
+    });
+    if copied.is_err() && staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    copied
+}
+
+fn stage(root: &Path, entries: &[DirEntry], staging: &Path) -> anyhow::Result<Summary> {
+    let started = Instant::now();
+    let target = |entry: &DirEntry| -> anyhow::Result<PathBuf> {
+        Ok(staging.join(entry.path().strip_prefix(root)?))
+    };
+
+    // Folders come first so that files can be copied into them in any
+    // order, and links are cheap enough to make along the way.
+    let mut dirs = Vec::new();
+    let mut files = Vec::new();
+    let mut links = 0;
+    for entry in entries {
+        let to = target(entry)?;
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&to)
+                .map_err(|err| anyhow!("Failed to create folder {:?}: {}", to, err))?;
+            dirs.push((to, entry.metadata()?));
+        } else if entry.file_type().is_symlink() {
+            symlink(fs::read_link(entry.path())?, &to)
+                .map_err(|err| anyhow!("Failed to link {:?}: {}", to, err))?;
+            links += 1;
+        } else {
+            files.push((entry.path(), to));
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let bytes = AtomicU64::new(0);
+    let failed = AtomicBool::new(false);
+    let error = Mutex::new(None);
+    let workers = thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+        .min(files.len().max(1));
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let (from, to) = match files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        Some(file) => file,
+                        None => break,
+                    };
+                    match copy_file(from, to) {
+                        Ok(size) => {
+                            bytes.fetch_add(size, Ordering::Relaxed);
+                        }
+                        Err(err) => {
+                            failed.store(true, Ordering::Relaxed);
+                            error.lock().unwrap().get_or_insert(anyhow!(
+                                "Failed to copy {:?}: {}",
+                                from,
+                                err
+                            ));
+                        }
+                    }
+                }
+            });
+        }
+    });
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+
+    // Writing into a folder changes its modification time, and a read-only
+    // one can't be written into, so folders are finished last and deepest
+    // first.
+    for (to, metadata) in dirs.iter().rev() {
+        File::open(to)?.set_modified(metadata.modified()?)?;
+        fs::set_permissions(to, metadata.permissions())?;
+    }
+
+    let summary = Summary {
+        files: files.len(),
+        links,
+        bytes: bytes.into_inner(),
+    };
+    println!(
+        "Copied {} files ({}) and {} links in {:.1}s",
+        summary.files,
+        format_size(summary.bytes),
+        summary.links,
+        started.elapsed().as_secs_f64()
+    );
+    Ok(summary)
+}
+
+/// Copy one file's contents, then its modification time and permissions,
+/// returning how many bytes were copied.
+fn copy_file(from: &Path, to: &Path) -> io::Result<u64> {
+    let metadata = fs::metadata(from)?;
+    let mut reader = File::open(from)?;
+    let mut writer = File::create(to)?;
+    let size = io::copy(&mut reader, &mut writer)?;
+    writer.set_modified(metadata.modified()?)?;
+    drop(writer);
+    fs::set_permissions(to, metadata.permissions())?;
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::testing::temp_dir;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn old() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+    }
+
+    /// A folder with nested files, ignore files at two levels, the default
+    /// excludes, an executable, an old file and a symlink.
+    fn fixture(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        let root = dir.join("source");
+        write(&root, ".gitignore", "*.log\nbuild/\n.env\n");
+        write(&root, ".env", "SECRET=1");
+        write(&root, "run.sh", "#!/bin/sh");
+        fs::set_permissions(root.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        write(&root, "old.txt", "old");
+        File::options()
+            .write(true)
+            .open(root.join("old.txt"))
+            .unwrap()
+            .set_modified(old())
+            .unwrap();
+        write(&root, "src/lib/deep.txt", "deep");
+        write(&root, "src/app.log", "log");
+        write(&root, "build/out", "out");
+        write(&root, "node_modules/pkg/index.js", "");
+        write(&root, "node_modules_backup/index.js", "");
+        write(&root, "docs/.shellyignore", "draft.md\n");
+        write(&root, "docs/draft.md", "");
+        write(&root, "docs/guide.md", "");
+        write(&root, ".git/HEAD", "");
+        symlink("old.txt", root.join("link")).unwrap();
+        dir
+    }
+
+    fn defaults() -> Vec<String> {
+        DEFAULT_EXCLUDE
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect()
+    }
+
+    fn walk(
+        root: &Path,
+        exclude: &[String],
+        include: &[String],
+        symlinks: SymlinkMode,
+    ) -> Vec<DirEntry> {
+        let filter = Filter::new(root, exclude, include).unwrap();
+        entries(root, filter, symlinks).unwrap()
+    }
+
+    fn relative(root: &Path, entries: &[DirEntry]) -> Vec<String> {
+        let mut paths: Vec<String> = entries
+            .iter()
+            .skip(1)
+            .map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn skips_ignored_and_excluded_paths() {
+        let dir = fixture("ignored");
+        let root = dir.join("source");
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::copy);
+        assert_eq!(
+            relative(&root, &entries),
+            vec![
+                ".gitignore",
+                "docs",
+                "docs/.shellyignore",
+                "docs/guide.md",
+                "link",
+                "node_modules_backup",
+                "node_modules_backup/index.js",
+                "old.txt",
+                "run.sh",
+                "src",
+                "src/lib",
+                "src/lib/deep.txt",
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_overrides_ignore_files() {
+        let dir = fixture("include");
+        let root = dir.join("source");
+        let exclude = vec![String::from("*.txt"), String::from("docs")];
+        let include = vec![String::from(".env"), String::from("build")];
+        let paths = relative(&root, &walk(&root, &exclude, &include, SymlinkMode::skip));
+        for path in [
+            ".env",
+            "build/out",
+            ".git/HEAD",
+            "node_modules/pkg/index.js",
+        ] {
+            assert!(paths.contains(&path.to_string()), "{} wasn't copied", path);
+        }
+        for path in ["old.txt", "src/lib/deep.txt", "docs", "link", "src/app.log"] {
+            assert!(!paths.contains(&path.to_string()), "{} was copied", path);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn includes_paths_inside_ignored_folders() {
+        let dir = fixture("include-inside");
+        let root = dir.join("source");
+        write(&root, ".gitignore", "*.log\nbuild/\n.env\nconfig/\n");
+        write(&root, "config/.env.example", "SECRET=");
+        write(&root, "config/secrets", "");
+        write(&root, "config/nested/other.example", "");
+
+        let include = vec![String::from("config/.env.example")];
+        let entries = walk(&root, &defaults(), &include, SymlinkMode::copy);
+        let paths = relative(&root, &entries);
+        assert!(paths.contains(&String::from("config")));
+        assert!(paths.contains(&String::from("config/.env.example")));
+        for path in ["config/secrets", "config/nested", "build", "build/out"] {
+            assert!(!paths.contains(&path.to_string()), "{} was copied", path);
+        }
+        copy(&root, &entries, &dir.join("project")).unwrap();
+        assert!(dir.join("project/config/.env.example").is_file());
+
+        let include = vec![String::from("*.example")];
+        let paths = relative(
+            &root,
+            &walk(&root, &defaults(), &include, SymlinkMode::copy),
+        );
+        assert!(paths.contains(&String::from("config/nested/other.example")));
+        assert!(!paths.contains(&String::from("config/secrets")));
+        assert!(!paths.contains(&String::from("build")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copies_nested_tree_with_permissions_and_mtimes() {
+        let dir = fixture("copy");
+        let root = dir.join("source");
+        let destination = dir.join("project");
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::copy);
+        let summary = copy(&root, &entries, &destination).unwrap();
+
+        assert_eq!(summary.files, 7);
+        assert_eq!(summary.links, 1);
+        assert_eq!(
+            fs::read_to_string(destination.join("src/lib/deep.txt")).unwrap(),
+            "deep"
+        );
+        let mode = fs::metadata(destination.join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        let modified = fs::metadata(destination.join("old.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(modified, old());
+        assert_eq!(
+            fs::read_link(destination.join("link")).unwrap(),
+            Path::new("old.txt")
+        );
+        assert!(!dir.join("project.partial").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copies_into_an_existing_empty_folder() {
+        let dir = fixture("existing");
+        let root = dir.join("source");
+        let destination = dir.join("project");
+        fs::create_dir(&destination).unwrap();
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::copy);
+        copy(&root, &entries, &destination.join(".")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(destination.join("src/lib/deep.txt")).unwrap(),
+            "deep"
+        );
+        assert!(!destination.join(".shelly-partial").exists());
+        assert!(!dir.join("project.partial").exists());
+        assert!(copy(&root, &entries, &destination.join(".")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn copies_next_to_what_is_already_there() {
+        let dir = fixture("nonempty");
+        let root = dir.join("source");
+        let destination = dir.join("project");
+        write(&destination, "notes.txt", "mine");
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::copy);
+        copy(&root, &entries, &destination.join(".")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(destination.join("notes.txt")).unwrap(),
+            "mine"
+        );
+        assert_eq!(
+            fs::read_to_string(destination.join("src/lib/deep.txt")).unwrap(),
+            "deep"
+        );
+        assert!(!destination.join(".shelly-partial").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn follows_symlinks() {
+        let dir = fixture("follow");
+        let root = dir.join("source");
+        let destination = dir.join("project");
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::follow);
+        copy(&root, &entries, &destination).unwrap();
+        let link = fs::symlink_metadata(destination.join("link")).unwrap();
+        assert!(link.is_file());
+        assert_eq!(fs::read_to_string(destination.join("link")).unwrap(), "old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fails_without_leaving_a_partial_copy() {
+        let dir = fixture("atomic");
+        let root = dir.join("source");
+        let destination = dir.join("project");
+        let entries = walk(&root, &defaults(), &[], SymlinkMode::copy);
+
+        fs::remove_file(root.join("src/lib/deep.txt")).unwrap();
+        assert!(copy(&root, &entries, &destination).is_err());
+        assert!(!destination.exists());
+        assert!(!dir.join("project.partial").exists());
+
+        write(&destination, "old.txt", "mine");
+        let err = copy(&root, &entries, &destination).unwrap_err();
+        assert!(err.to_string().contains("already has old.txt"), "{}", err);
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 1);
+        assert_eq!(
+            fs::read_to_string(destination.join("old.txt")).unwrap(),
+            "mine"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
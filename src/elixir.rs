@@ -1,14 +1,22 @@
 use serde_derive::{Deserialize, Serialize};
 
 use crate::target::{
-    generate_hash, Dependencies, Dependency, LanguageTarget, ProgramCommand, Shell,
+    generate_hash, Dependencies, Dependency, LanguageTarget, Lockfile, ProgramCommand, Shell,
     SupportedLanguage,
 };
+use crate::templates::{self, BuiltinTemplates, TemplateDirs};
+use crate::version::Requirement;
 
 const BUILD_TEMPLATE: &str = include_str!("./templates/elixir/mix.exs.tmpl");
 const SOURCE_TEMPLATE: &str = include_str!("./templates/elixir/parser.ex.tmpl");
 const SHELL_TEMPLATE: &str = include_str!("./templates/elixir/shell.sh.tmpl");
 
+pub const TEMPLATES: BuiltinTemplates<3> = [
+    ("mix.exs.tmpl", BUILD_TEMPLATE),
+    ("parser.ex.tmpl", SOURCE_TEMPLATE),
+    ("shell.sh.tmpl", SHELL_TEMPLATE),
+];
+
 #[derive(Deserialize, Serialize)]
 pub struct Context {
     pub applications: Vec<String>,
@@ -16,23 +24,30 @@ pub struct Context {
     pub dep_string: String,
 }
 
-pub fn new(deps: Dependencies, shell: bool) -> LanguageTarget<Context> {
-    LanguageTarget::new(
-        ("mix.exs", BUILD_TEMPLATE),
+pub fn new(
+    deps: Dependencies,
+    shell: bool,
+    templates: &TemplateDirs,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    let mut target = LanguageTarget::new(
+        (
+            String::from("mix.exs"),
+            templates.load("mix.exs.tmpl", BUILD_TEMPLATE)?,
+        ),
         Context {
             applications: generate_applications(deps.clone()),
-            dep_string: generate_dep_string(deps.clone()),
+            dep_string: generate_dep_string(deps.clone())?,
             deps: deps.clone(),
         },
         generate_hash(deps, SupportedLanguage::elixir),
-        ProgramCommand::new(
+        vec![ProgramCommand::new(
             String::from("mix"),
             vec![
                 "do".to_string(),
                 "deps.get,".to_string(),
                 "deps.compile".to_string(),
             ],
-        ),
+        )],
         if shell {
             Some(Shell::new(
                 Box::new(|| {
@@ -41,23 +56,103 @@ pub fn new(deps: Dependencies, shell: bool) -> LanguageTarget<Context> {
                         vec!["-S".to_string(), "mix".to_string()],
                     ))
                 }),
-                SHELL_TEMPLATE,
+                templates.load("shell.sh.tmpl", SHELL_TEMPLATE)?,
             ))
         } else {
             None
         },
-        "lib",
-        vec![("parser.ex", SOURCE_TEMPLATE)],
+        String::from("lib"),
+        vec![(
+            String::from("parser.ex"),
+            templates.load("parser.ex.tmpl", SOURCE_TEMPLATE)?,
+        )],
+    );
+    target.set_offline_commands(vec![ProgramCommand::new(
+        String::from("mix"),
+        vec![
+            "do".to_string(),
+            "deps.get,".to_string(),
+            "deps.compile".to_string(),
+        ],
     )
+    .with_env("MIX_HEX_OFFLINE", "1")
+    .with_env("HEX_OFFLINE", "1")]);
+    target.set_lockfile(Lockfile::new(
+        String::from("mix.lock"),
+        ProgramCommand::new(String::from("mix"), vec!["deps.get".to_string()]),
+    ));
+    target.add_vars(templates::vars(&[("app", "script"), ("module", "Script")]));
+    Ok(target)
 }
 
-fn generate_dep_string(deps: Dependencies) -> String {
-    deps.iter()
-        .map(|Dependency { name, version }| format!("{{:{}, \"~> {}\"}}", name, version))
-        .collect::<Vec<String>>()
-        .join(", ")
+fn generate_dep_string(deps: Dependencies) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(generate_dep)
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join(", "))
 }
 
+fn generate_dep(dep: &Dependency) -> anyhow::Result<String> {
+    let mut parts = vec![format!(":{}", dep.name)];
+
+    if let Some(requirement) = dep.requirement_string(generate_requirement)? {
+        parts.push(format!("\"{}\"", requirement));
+    }
+    if let Some(git) = &dep.git {
+        parts.push(format!("git: \"{}\"", git));
+    }
+    if let Some(git_ref) = &dep.git_ref {
+        parts.push(format!("ref: \"{}\"", git_ref));
+    }
+    if let Some(path) = &dep.path {
+        parts.push(format!("path: \"{}\"", path));
+    }
+    if let Some(registry) = &dep.registry {
+        parts.push(format!("repo: \"{}\"", registry));
+    }
+    if let Some(organization) = &dep.organization {
+        parts.push(format!("organization: \"{}\"", organization));
+    }
+    match dep.only.as_slice() {
+        [] => {}
+        [env] => parts.push(format!("only: :{}", env)),
+        envs => parts.push(format!(
+            "only: [{}]",
+            envs.iter()
+                .map(|env| format!(":{}", env))
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+
+    Ok(format!("{{{}}}", parts.join(", ")))
+}
+
+/// Mix needs at least a major and minor version after `~>`, and full
+/// versions everywhere else.
+pub fn generate_requirement(requirement: &Requirement) -> anyhow::Result<String> {
+    Ok(match requirement {
+        Requirement::Compatible(version) if version.numbers.len() < 2 => {
+            format!("~> {}", version.extended())
+        }
+        Requirement::Compatible(version) => format!("~> {}", version),
+        Requirement::Exact(version) => format!("== {}", version.padded()),
+        Requirement::Tilde(version) => format!("~> {}", version.extended()),
+        requirement => requirement
+            .comparators()
+            .iter()
+            .map(|(operator, version)| format!("{} {}", operator.symbol(), version.padded()))
+            .collect::<Vec<String>>()
+            .join(" and "),
+    })
+}
+
+/// Only the dependencies available in `dev`, which `iex -S mix` runs in, can
+/// be started as applications.
 fn generate_applications(deps: Dependencies) -> Vec<String> {
-    deps.iter().map(|dep| dep.name.clone()).collect()
+    deps.iter()
+        .filter(|dep| dep.only.is_empty() || dep.only.iter().any(|env| env == "dev"))
+        .map(|dep| dep.name.clone())
+        .collect()
 }
@@ -1,5 +1,16 @@
+pub mod cache;
 pub mod config;
+pub mod custom;
+pub mod directory;
 pub mod elixir;
+pub mod mirror;
 pub mod node;
 pub mod opts;
+pub mod python;
+pub mod ruby;
+pub mod rust;
 pub mod target;
+pub mod templates;
+#[cfg(test)]
+pub mod testing;
+pub mod version;
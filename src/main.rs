@@ -4,24 +4,27 @@ use std::path;
 use anyhow::{anyhow, Result};
 use clap::Clap;
 
-use shelly::config::Config;
-use shelly::opts::Opts;
-use shelly::target::{copy_build_directory, pull_git_repo, Target};
+use shelly::cache;
+use shelly::opts::{CacheCommand, Command, Opts, TemplatesCommand};
+use shelly::target::{copy_build_directory, pull_git_repo, warm_cache, Target};
+use shelly::templates;
 
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    let config_file = if let Some(cfg) = opts.get_config() {
-        fs::read_to_string(cfg)?
+    let (config_file, config_dir) = if let Some(cfg) = opts.get_config() {
+        let config_dir = path::Path::new(&cfg)
+            .parent()
+            .map(|dir| dir.to_path_buf())
+            .unwrap_or_default();
+        (fs::read_to_string(cfg)?, config_dir)
     } else {
-        let home_path = path::PathBuf::from(
-            std::env::var("HOME").map_err(|err| anyhow!("Failed to read $HOME: {}", err))?,
-        );
-        let path = home_path.join(".config").join("shelly");
+        let path = shelly::config::home_dir()?;
 
         if path.join("shelly.yml").as_path().exists() {
-            fs::read_to_string(path.join("shelly.yml"))
-                .map_err(|err| anyhow!("Failed to read existing `shelly.yml`: {}", err))?
+            let config_file = fs::read_to_string(path.join("shelly.yml"))
+                .map_err(|err| anyhow!("Failed to read existing `shelly.yml`: {}", err))?;
+            (config_file, path)
         } else {
             let default = shelly::config::default();
             let default_config = serde_yaml::to_string(&default)?;
@@ -29,23 +32,78 @@ fn main() -> Result<()> {
                 .map_err(|err| anyhow!("Failed to create config directory: {}", err))?;
             fs::write(path.join("shelly.yml"), default_config.clone())
                 .map_err(|err| anyhow!("Failed to write default config file: {}", err))?;
-            default_config
+            (default_config, path)
         }
     };
+    // Template paths in the config are relative to it, so pin its folder
+    // down before any target changes directory.
+    let config_dir = std::env::current_dir()?.join(config_dir);
 
-    let config: Config = serde_yaml::from_str(config_file.as_str())
-        .map_err(|err| anyhow!("Error parsing config file: {}", err))?;
+    if let Some(Command::Templates(TemplatesCommand::Eject { language, force })) =
+        opts.get_command()
+    {
+        return templates::eject(language, *force);
+    }
 
-    let target_name = opts.get_target().unwrap_or(config.default_target);
+    let config = shelly::config::parse(&config_file)?;
 
-    let target = config
-        .targets
-        .get(&target_name)
-        .ok_or_else(|| anyhow!("Target not specified in `shelly.yml` file"))?;
+    if let Some(Command::Cache(command)) = opts.get_command() {
+        let build_dir = std::env::current_dir()?.join(&config.build_dir);
+        return match command {
+            CacheCommand::Ls => cache::list(&build_dir),
+            CacheCommand::Rm { entry } => cache::remove_matching(&build_dir, entry),
+            CacheCommand::Prune {
+                older_than,
+                max_size,
+            } => cache::prune(&build_dir, *older_than, *max_size),
+            CacheCommand::Clear => cache::clear(&build_dir),
+        };
+    }
+
+    if let Some(Command::Warm) = opts.get_command() {
+        return warm_cache(&config, &opts, &config_dir);
+    }
+
+    if let Some(Command::Lock { target }) = opts.get_command() {
+        return match config.target(target)? {
+            Target::Internal(t) => t.lock(opts, config.build_dir, &config_dir),
+            Target::Custom(t) => t.lock(opts, config.build_dir, &config_dir),
+            Target::Repo(_) | Target::Directory(_) => {
+                Err(anyhow!("Only Internal and Custom targets have lockfiles"))
+            }
+        };
+    }
+
+    let target_name = opts
+        .get_target()
+        .unwrap_or_else(|| config.default_target.clone());
+
+    let target = config.target(&target_name)?;
 
     match target {
-        Target::Internal(t) => t.execute(opts, config.build_dir),
-        Target::Repo(repo) => pull_git_repo(opts.get_path(), repo, opts.get_shell()),
-        Target::Directory(dir) => copy_build_directory(opts.get_path(), dir, opts.get_shell()),
+        Target::Internal(t) => t.execute(
+            opts,
+            config.build_dir,
+            config.cache.unwrap_or(true),
+            &config_dir,
+        ),
+        Target::Custom(t) => t.execute(
+            opts,
+            config.build_dir,
+            config.cache.unwrap_or(true),
+            &config_dir,
+        ),
+        Target::Repo(repo) => {
+            let mirror_ttl = config.mirror_ttl()?;
+            pull_git_repo(
+                target_name,
+                &opts,
+                &repo,
+                config.build_dir,
+                config.cache.unwrap_or(true),
+                mirror_ttl,
+            )
+        }
+        Target::Directory(dir) => copy_build_directory(opts.get_path(), &dir, opts.get_shell()),
     }
 }
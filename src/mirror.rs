@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+
+use crate::target::ProgramCommand;
+
+/// Marks when a mirror was last fetched into, by its modification time.
+const FETCHED: &str = "shelly-fetched";
+
+/// The folder in `build_dir` holding the bare mirror of the repo at `url`.
+pub fn path(build_dir: &Path, url: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    build_dir
+        .join("mirrors")
+        .join(format!("{:X}.git", hasher.finalize()))
+}
+
+/// Mirror the repo at `url` into `build_dir`, or fetch into the existing
+/// mirror once it's older than `ttl`, returning where the mirror is.  A
+/// failed fetch only warns, since the mirror is still usable as it is.
+pub fn update(
+    build_dir: &Path,
+    url: &str,
+    ttl: Duration,
+    offline: bool,
+) -> anyhow::Result<PathBuf> {
+    let mirror = path(build_dir, url);
+    let git = |args: Vec<&str>| {
+        ProgramCommand::new(
+            String::from("git"),
+            args.into_iter().map(String::from).collect(),
+        )
+        .run()
+    };
+
+    if !mirror.is_dir() {
+        if offline {
+            return Err(anyhow!(
+                "Can't clone {} offline, it hasn't been mirrored yet",
+                url
+            ));
+        }
+        println!("Mirroring {}...", url);
+        // Cloned next to the mirror and then renamed into place, so an
+        // interrupted clone isn't mistaken for a mirror.
+        let partial = mirror.with_extension("partial");
+        if partial.exists() {
+            fs::remove_dir_all(&partial)?;
+        }
+        git(vec![
+            "clone",
+            "--mirror",
+            "--quiet",
+            url,
+            &partial.to_string_lossy(),
+        ])?;
+        fs::rename(&partial, &mirror)
+            .map_err(|err| anyhow!("Failed to move mirror into place: {}", err))?;
+    } else if !offline && is_stale(&mirror, ttl) {
+        println!("Updating mirror of {}...", url);
+        if let Err(err) = git(vec![
+            "-C",
+            &mirror.to_string_lossy(),
+            "fetch",
+            "--prune",
+            "--quiet",
+        ]) {
+            println!("Failed to update mirror, using it as it is: {}", err);
+            return Ok(mirror);
+        }
+    } else {
+        return Ok(mirror);
+    }
+
+    fs::write(mirror.join(FETCHED), "")
+        .map_err(|err| anyhow!("Failed to mark mirror as fetched: {}", err))?;
+    Ok(mirror)
+}
+
+fn is_stale(mirror: &Path, ttl: Duration) -> bool {
+    fs::metadata(mirror.join(FETCHED))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|fetched| SystemTime::now().duration_since(fetched).ok())
+        .is_none_or(|age| age > ttl)
+}
+
+/// The commit `git_ref`, or `HEAD` without one, points at in the repo at
+/// `repo`.
+pub fn resolve(repo: &Path, git_ref: Option<&str>) -> anyhow::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", git_ref.unwrap_or("HEAD")),
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} isn't a commit in {:?}",
+            git_ref.unwrap_or("HEAD"),
+            repo
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
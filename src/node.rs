@@ -1,64 +1,399 @@
+use std::fmt;
 use std::fs;
 
+use anyhow::anyhow;
 use serde_derive::{Deserialize, Serialize};
 
 use crate::target::{
-    generate_hash, Dependencies, Dependency, LanguageTarget, ProgramCommand, Shell,
-    SupportedLanguage,
+    generate_hash, Dependencies, Dependency, LanguageTarget, LanguageTemplate, Lockfile,
+    ProgramCommand, Shell, SupportedLanguage,
 };
+use crate::templates::{self, BuiltinTemplates, TemplateDirs};
+use crate::version::{Operator, Requirement};
 
 const BUILD_TEMPLATE: &str = include_str!("./templates/node/package.json.tmpl");
 const SOURCE_TEMPLATE: &str = include_str!("./templates/node/index.js.tmpl");
 const SHELL_TEMPLATE: &str = include_str!("./templates/node/shell.sh.tmpl");
 
+const BUN_SHELL_TEMPLATE: &str = include_str!("./templates/node/bun.shell.sh.tmpl");
+
+const DENO_BUILD_TEMPLATE: &str = include_str!("./templates/node/deno.json.tmpl");
+const DENO_SOURCE_TEMPLATE: &str = include_str!("./templates/node/deno.index.js.tmpl");
+const DENO_SHELL_TEMPLATE: &str = include_str!("./templates/node/deno.shell.sh.tmpl");
+
+pub const TEMPLATES: BuiltinTemplates<7> = [
+    ("package.json.tmpl", BUILD_TEMPLATE),
+    ("index.js.tmpl", SOURCE_TEMPLATE),
+    ("shell.sh.tmpl", SHELL_TEMPLATE),
+    ("bun.shell.sh.tmpl", BUN_SHELL_TEMPLATE),
+    ("deno.json.tmpl", DENO_BUILD_TEMPLATE),
+    ("deno.index.js.tmpl", DENO_SOURCE_TEMPLATE),
+    ("deno.shell.sh.tmpl", DENO_SHELL_TEMPLATE),
+];
+
+/// The JavaScript runtime, and package manager, used to install and run a
+/// node target.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum Runtime {
+    #[default]
+    npm,
+    pnpm,
+    yarn,
+    bun,
+    deno,
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s: &'static str = match self {
+            Runtime::npm => "npm",
+            Runtime::pnpm => "pnpm",
+            Runtime::yarn => "yarn",
+            Runtime::bun => "bun",
+            Runtime::deno => "deno",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Runtime {
+    /// The lockfile the runtime writes next to the build file on install.
+    pub fn lockfile(&self) -> &'static str {
+        match self {
+            Runtime::npm => "package-lock.json",
+            Runtime::pnpm => "pnpm-lock.yaml",
+            Runtime::yarn => "yarn.lock",
+            Runtime::bun => "bun.lock",
+            Runtime::deno => "deno.lock",
+        }
+    }
+
+    fn build_template(&self, templates: &TemplateDirs) -> anyhow::Result<LanguageTemplate> {
+        Ok(match self {
+            Runtime::deno => (
+                String::from("deno.json"),
+                templates.load("deno.json.tmpl", DENO_BUILD_TEMPLATE)?,
+            ),
+            _ => (
+                String::from("package.json"),
+                templates.load("package.json.tmpl", BUILD_TEMPLATE)?,
+            ),
+        })
+    }
+
+    fn source_template(&self, templates: &TemplateDirs) -> anyhow::Result<LanguageTemplate> {
+        Ok(match self {
+            Runtime::deno => (
+                String::from("index.js"),
+                templates.load("deno.index.js.tmpl", DENO_SOURCE_TEMPLATE)?,
+            ),
+            _ => (
+                String::from("index.js"),
+                templates.load("index.js.tmpl", SOURCE_TEMPLATE)?,
+            ),
+        })
+    }
+
+    fn shell_template(&self, templates: &TemplateDirs) -> anyhow::Result<String> {
+        match self {
+            Runtime::bun => templates.load("bun.shell.sh.tmpl", BUN_SHELL_TEMPLATE),
+            Runtime::deno => templates.load("deno.shell.sh.tmpl", DENO_SHELL_TEMPLATE),
+            _ => templates.load("shell.sh.tmpl", SHELL_TEMPLATE),
+        }
+    }
+
+    fn install_command(&self) -> ProgramCommand {
+        let args = match self {
+            Runtime::npm => vec!["i".to_string()],
+            _ => vec!["install".to_string()],
+        };
+        ProgramCommand::new(self.to_string(), args)
+    }
+
+    /// Resolves the dependencies into the lockfile without installing them,
+    /// where the runtime can.  yarn and deno have no such mode, so locking
+    /// with them downloads everything, only to throw it away.
+    fn lock_command(&self) -> ProgramCommand {
+        let args = match self {
+            Runtime::npm => vec!["i", "--package-lock-only"],
+            Runtime::pnpm | Runtime::bun => vec!["install", "--lockfile-only"],
+            Runtime::yarn | Runtime::deno => vec!["install"],
+        };
+        ProgramCommand::new(
+            self.to_string(),
+            args.into_iter().map(String::from).collect(),
+        )
+    }
+
+    /// Installs from the package manager's local cache only, where the
+    /// runtime can.
+    fn offline_install_command(&self) -> Option<ProgramCommand> {
+        let args = match self {
+            Runtime::npm => vec!["i", "--offline"],
+            Runtime::pnpm | Runtime::yarn => vec!["install", "--offline"],
+            Runtime::bun | Runtime::deno => return None,
+        };
+        Some(ProgramCommand::new(
+            self.to_string(),
+            args.into_iter().map(String::from).collect(),
+        ))
+    }
+
+    fn shell_command(&self) -> anyhow::Result<ProgramCommand> {
+        match self {
+            Runtime::bun => Ok(ProgramCommand::new(
+                String::from("bun"),
+                vec!["repl".to_string()],
+            )),
+            Runtime::deno => Ok(ProgramCommand::new(
+                String::from("deno"),
+                vec![
+                    "repl".to_string(),
+                    "--allow-all".to_string(),
+                    "--eval-file=src/index.js".to_string(),
+                ],
+            )),
+            _ => {
+                let source = fs::read_to_string("./src/index.js")?;
+                Ok(ProgramCommand::new(
+                    String::from("node"),
+                    vec![
+                        "-i".to_string(),
+                        "--experimental-repl-await".to_string(),
+                        "-e".to_string(),
+                        source,
+                    ],
+                ))
+            }
+        }
+    }
+
+    /// Builds installed by different runtimes are laid out differently, so
+    /// anything but the original `npm` gets its own cache entries.
+    fn hash_key(&self) -> String {
+        match self {
+            Runtime::npm => SupportedLanguage::node.to_string(),
+            _ => format!("{}:{}", SupportedLanguage::node, self),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Context {
     pub deps: Dependencies,
     pub dep_string: String,
+    pub dev_dep_string: String,
     pub packages: Vec<String>,
+    pub imports: Vec<Import>,
 }
 
-pub fn new(deps: Dependencies, shell: bool) -> LanguageTarget<Context> {
-    LanguageTarget::new(
-        ("package.json", BUILD_TEMPLATE),
+/// A package, and the identifier the source templates bind its default
+/// export to.
+#[derive(Deserialize, Serialize)]
+pub struct Import {
+    pub name: String,
+    pub binding: String,
+}
+
+/// Words that can't be used as a binding in module code.
+const RESERVED_WORDS: [&str; 47] = [
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+    "arguments",
+];
+
+pub fn new(
+    deps: Dependencies,
+    shell: bool,
+    runtime: Runtime,
+    templates: &TemplateDirs,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    let source_template = runtime.source_template(templates)?;
+    let lockfile = Lockfile::new(runtime.lockfile().to_string(), runtime.lock_command());
+    let offline_install = runtime.offline_install_command();
+    // Deno has no separate place for development dependencies.
+    let (dev_deps, runtime_deps): (Vec<&Dependency>, Vec<&Dependency>) = deps
+        .iter()
+        .partition(|dep| dep.is_dev_only() && !matches!(runtime, Runtime::deno));
+    let dep_string = generate_dep_string(&runtime_deps, &runtime)?;
+    let dev_dep_string = generate_dep_string(&dev_deps, &runtime)?;
+    let mut target = LanguageTarget::new(
+        runtime.build_template(templates)?,
         Context {
             deps: deps.clone(),
-            dep_string: generate_dep_string(deps.clone()),
+            dep_string,
+            dev_dep_string,
             packages: generate_packages(deps.clone()),
+            imports: generate_imports(&deps),
         },
-        generate_hash(deps, SupportedLanguage::node),
-        ProgramCommand::new(String::from("npm"), vec!["i".to_string()]),
+        generate_hash(deps, runtime.hash_key()),
+        vec![runtime.install_command()],
         if shell {
+            let shell_template = runtime.shell_template(templates)?;
             Some(Shell::new(
-                Box::new(|| {
-                    let source = fs::read_to_string("./src/index.js")?;
-                    Ok(ProgramCommand::new(
-                        String::from("node"),
-                        vec![
-                            "-i".to_string(),
-                            "--experimental-repl-await".to_string(),
-                            "-e".to_string(),
-                            source,
-                        ],
-                    ))
-                }),
-                SHELL_TEMPLATE,
+                Box::new(move || runtime.shell_command()),
+                shell_template,
             ))
         } else {
             None
         },
-        "src",
-        vec![("index.js", SOURCE_TEMPLATE)],
-    )
+        String::from("src"),
+        vec![source_template],
+    );
+    target.set_lockfile(lockfile);
+    if let Some(command) = offline_install {
+        target.set_offline_commands(vec![command]);
+    }
+    target.add_vars(templates::vars(&[("name", "node"), ("author", "shelly")]));
+    Ok(target)
 }
 
-fn generate_dep_string(deps: Dependencies) -> String {
-    deps.iter()
-        .map(|Dependency { name, version }| format!("\"{}\": \"^{}\"", name, version))
-        .collect::<Vec<String>>()
-        .join(",\n")
+fn generate_dep_string(deps: &[&Dependency], runtime: &Runtime) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(|dep| generate_dep(dep, runtime))
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join(",\n"))
+}
+
+fn generate_dep(dep: &Dependency, runtime: &Runtime) -> anyhow::Result<String> {
+    let specifier = match runtime {
+        Runtime::deno => {
+            dep.unsupported("deno", &["git", "path", "registry", "organization"])?;
+            match dep.requirement_string(generate_requirement)? {
+                Some(requirement) => format!("npm:{}@{}", dep.name, requirement),
+                None => format!("npm:{}", dep.name),
+            }
+        }
+        _ => {
+            dep.unsupported("node", &["registry", "organization"])?;
+            match (&dep.git, &dep.git_ref, &dep.path) {
+                (Some(git), Some(git_ref), _) => format!("git+{}#{}", git, git_ref),
+                (Some(git), None, _) => format!("git+{}", git),
+                (None, _, Some(path)) => format!("file:{}", path),
+                _ => dep
+                    .requirement_string(generate_requirement)?
+                    .unwrap_or_else(|| String::from("*")),
+            }
+        }
+    };
+    Ok(format!("\"{}\": \"{}\"", dep.name, specifier))
+}
+
+pub fn generate_requirement(requirement: &Requirement) -> anyhow::Result<String> {
+    Ok(match requirement {
+        Requirement::Compatible(version) | Requirement::Caret(version) => format!("^{}", version),
+        Requirement::Exact(version) => version.padded().to_string(),
+        Requirement::Tilde(version) => format!("~{}", version),
+        Requirement::Range(comparators) => comparators
+            .iter()
+            .map(|(operator, version)| match operator {
+                Operator::Equal => Ok(format!("={}", version.padded())),
+                Operator::NotEqual => Err(anyhow!("npm has no `!=` for versions")),
+                operator => Ok(format!("{}{}", operator.symbol(), version)),
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?
+            .join(" "),
+        Requirement::Any => String::from("*"),
+    })
 }
 
 fn generate_packages(deps: Dependencies) -> Vec<String> {
     deps.iter().map(|dep| dep.name.clone()).collect()
 }
+
+fn generate_imports(deps: &[Dependency]) -> Vec<Import> {
+    deps.iter()
+        .map(|dep| Import {
+            name: dep.name.clone(),
+            binding: binding(&dep.name),
+        })
+        .collect()
+}
+
+/// A valid identifier for the package `name`: the scope is dropped, and
+/// anything but letters and digits starts a new camel-cased word, so
+/// `@types/date-fns` becomes `dateFns`.
+fn binding(name: &str) -> String {
+    let unscoped = name.rsplit('/').next().unwrap_or(name);
+    let mut binding = String::new();
+    for word in unscoped
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        if binding.is_empty() {
+            binding.push_str(word);
+        } else {
+            let mut chars = word.chars();
+            binding.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+            binding.push_str(chars.as_str());
+        }
+    }
+    if binding.is_empty() || binding.starts_with(|c: char| c.is_ascii_digit()) {
+        binding.insert(0, '_');
+    }
+    if RESERVED_WORDS.contains(&binding.as_str()) {
+        binding.push('_');
+    }
+    binding
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_packages_to_valid_identifiers() {
+        assert_eq!(binding("axios"), "axios");
+        assert_eq!(binding("date-fns"), "dateFns");
+        assert_eq!(binding("@types/lodash.merge"), "lodashMerge");
+        assert_eq!(binding("JSONStream"), "JSONStream");
+        assert_eq!(binding("7zip"), "_7zip");
+        assert_eq!(binding("package"), "package_");
+    }
+}
@@ -1,5 +1,9 @@
+use std::time::Duration;
+
 use clap::Clap;
 
+use crate::target::{Dependency, SupportedLanguage};
+
 /// Generate dynamic, scripting language projects with dependencies for
 /// quick CLI feedback loops.
 #[derive(Clap)]
@@ -22,6 +26,80 @@ pub struct Opts {
 
     #[clap(long)]
     no_cache: bool,
+
+    /// Add a dependency to the target, or change its version
+    #[clap(long = "dep", value_name = "name@version", number_of_values = 1)]
+    deps: Vec<Dependency>,
+
+    /// Leave one of the target's dependencies out
+    #[clap(long, value_name = "name", number_of_values = 1)]
+    without: Vec<String>,
+
+    /// Set a template variable, overriding the target's `vars`
+    #[clap(long = "var", value_name = "key=value", parse(try_from_str = parse_var), number_of_values = 1)]
+    vars: Vec<(String, String)>,
+
+    /// Fail instead of prompting for template variables that aren't set
+    #[clap(long)]
+    no_input: bool,
+
+    /// Resolve dependencies again, instead of reusing the stored lockfile
+    #[clap(long)]
+    refresh_lock: bool,
+
+    /// Only use cached builds and the package managers' local caches
+    #[clap(long)]
+    offline: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Manage the templates that projects are generated from
+    Templates(TemplatesCommand),
+    /// Resolve a target's dependencies into a lockfile in the build
+    /// directory, without generating a project
+    Lock { target: String },
+    /// Build every Internal target into the build directory, so later runs
+    /// restore them instead of installing
+    Warm,
+    /// Inspect and clean up the builds stored in the build directory
+    Cache(CacheCommand),
+}
+
+#[derive(Clap)]
+pub enum CacheCommand {
+    /// List the cached builds, most recently used first
+    Ls,
+    /// Remove the cached builds for a target, or the one with a hash
+    Rm { entry: String },
+    /// Remove the least recently used builds
+    Prune {
+        /// Remove builds unused for longer than this, like `30d` or `12h`
+        #[clap(long, parse(try_from_str = parse_duration))]
+        older_than: Option<Duration>,
+
+        /// Then remove builds until the cache fits in this, like `5G`
+        #[clap(long, parse(try_from_str = parse_size))]
+        max_size: Option<u64>,
+    },
+    /// Remove every cached build
+    Clear,
+}
+
+#[derive(Clap)]
+pub enum TemplatesCommand {
+    /// Copy the built-in templates for a language into
+    /// `~/.config/shelly/templates` for editing
+    Eject {
+        language: SupportedLanguage,
+
+        /// Overwrite templates that have already been ejected
+        #[clap(short, long)]
+        force: bool,
+    },
 }
 
 impl Opts {
@@ -48,4 +126,132 @@ impl Opts {
     pub fn get_no_cache(&self) -> bool {
         self.no_cache
     }
+
+    pub fn get_deps(&self) -> Vec<Dependency> {
+        self.deps.clone()
+    }
+
+    pub fn get_without(&self) -> Vec<String> {
+        self.without.clone()
+    }
+
+    pub fn get_vars(&self) -> Vec<(String, String)> {
+        self.vars.clone()
+    }
+
+    pub fn get_no_input(&self) -> bool {
+        self.no_input
+    }
+
+    pub fn get_refresh_lock(&self) -> bool {
+        self.refresh_lock
+    }
+
+    pub fn get_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn get_command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+}
+
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let error = || {
+        format!(
+            "expected a number followed by s, m, h, d or w, got {}",
+            duration
+        )
+    };
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(split);
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    number
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{} is too long", duration))
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let error = || {
+        format!(
+            "expected a number of bytes, or one followed by K, M, G or T, got {}",
+            size
+        )
+    };
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let multiplier: u64 = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        "T" | "TB" => 1 << 40,
+        _ => return Err(error()),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{} is too large", size))
+}
+
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    let mut parts = var.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected key=value, got {}", var)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("99999999999999999w").is_err());
+        assert_eq!(
+            parse_duration(&format!("{}s", u64::MAX)),
+            Ok(Duration::from_secs(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert_eq!(parse_size("2k"), Ok(2 << 10));
+        assert_eq!(parse_size("3MB"), Ok(3 << 20));
+        assert_eq!(parse_size("5G"), Ok(5 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("5P").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("99999999T").is_err());
+        assert_eq!(parse_size(&u64::MAX.to_string()), Ok(u64::MAX));
+    }
 }
@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::target::{
+    generate_hash, Dependencies, Dependency, LanguageTarget, ProgramCommand, Shell,
+    SupportedLanguage,
+};
+use crate::templates::{BuiltinTemplates, TemplateDirs};
+use crate::version::Requirement;
+
+const BUILD_TEMPLATE: &str = include_str!("./templates/python/requirements.txt.tmpl");
+const SOURCE_TEMPLATE: &str = include_str!("./templates/python/script.py.tmpl");
+const SHELL_TEMPLATE: &str = include_str!("./templates/python/shell.sh.tmpl");
+
+pub const TEMPLATES: BuiltinTemplates<3> = [
+    ("requirements.txt.tmpl", BUILD_TEMPLATE),
+    ("script.py.tmpl", SOURCE_TEMPLATE),
+    ("shell.sh.tmpl", SHELL_TEMPLATE),
+];
+
+const VENV_DIRECTORY: &str = ".venv";
+
+#[derive(Deserialize, Serialize)]
+pub struct Context {
+    pub deps: Dependencies,
+    pub dep_string: String,
+}
+
+pub fn new(
+    deps: Dependencies,
+    shell: bool,
+    templates: &TemplateDirs,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    let mut target = LanguageTarget::new(
+        (
+            String::from("requirements.txt"),
+            templates.load("requirements.txt.tmpl", BUILD_TEMPLATE)?,
+        ),
+        Context {
+            dep_string: generate_dep_string(deps.clone())?,
+            deps: deps.clone(),
+        },
+        generate_hash(deps, SupportedLanguage::python),
+        vec![
+            ProgramCommand::new(
+                String::from("python3"),
+                vec![
+                    "-m".to_string(),
+                    "venv".to_string(),
+                    VENV_DIRECTORY.to_string(),
+                ],
+            ),
+            ProgramCommand::new(
+                venv_bin("pip"),
+                vec![
+                    "install".to_string(),
+                    "-r".to_string(),
+                    "requirements.txt".to_string(),
+                ],
+            ),
+        ],
+        if shell {
+            Some(Shell::new(
+                Box::new(|| {
+                    // Go through the venv's python, using IPython when it's
+                    // been installed.
+                    let mut args = vec!["-i".to_string(), "src/script.py".to_string()];
+                    if Path::new(venv_bin("ipython").as_str()).exists() {
+                        args.insert(0, "IPython".to_string());
+                        args.insert(0, "-m".to_string());
+                    }
+                    Ok(ProgramCommand::new(venv_bin("python"), args))
+                }),
+                templates.load("shell.sh.tmpl", SHELL_TEMPLATE)?,
+            ))
+        } else {
+            None
+        },
+        String::from("src"),
+        vec![(
+            String::from("script.py"),
+            templates.load("script.py.tmpl", SOURCE_TEMPLATE)?,
+        )],
+    );
+    // The venv's scripts and `activate` point at where it was created.
+    target.set_relocate(vec![format!("{}/bin", VENV_DIRECTORY)]);
+    Ok(target)
+}
+
+fn venv_bin(program: &str) -> String {
+    format!("./{}/bin/{}", VENV_DIRECTORY, program)
+}
+
+fn generate_dep_string(deps: Dependencies) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(generate_dep)
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join("\n"))
+}
+
+fn generate_dep(dep: &Dependency) -> anyhow::Result<String> {
+    dep.unsupported("python", &["registry", "organization", "only"])?;
+    Ok(match (&dep.git, &dep.git_ref, &dep.path) {
+        (Some(git), Some(git_ref), _) => format!("{} @ git+{}@{}", dep.name, git, git_ref),
+        (Some(git), None, _) => format!("{} @ git+{}", dep.name, git),
+        (None, _, Some(path)) => path.clone(),
+        _ => format!(
+            "{}{}",
+            dep.name,
+            dep.requirement_string(generate_requirement)?
+                .unwrap_or_default()
+        ),
+    })
+}
+
+/// `~=` needs at least a major and minor version, and only lets the last
+/// one given change.
+pub fn generate_requirement(requirement: &Requirement) -> anyhow::Result<String> {
+    Ok(match requirement {
+        Requirement::Compatible(version) if version.numbers.len() < 2 => {
+            format!("~={}", version.extended())
+        }
+        Requirement::Compatible(version) => format!("~={}", version),
+        Requirement::Exact(version) => format!("=={}", version),
+        Requirement::Tilde(version) => format!("~={}", version.extended()),
+        Requirement::Any => String::new(),
+        requirement => requirement
+            .comparators()
+            .iter()
+            .map(|(operator, version)| format!("{}{}", operator.symbol(), version))
+            .collect::<Vec<String>>()
+            .join(","),
+    })
+}
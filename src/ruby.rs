@@ -0,0 +1,161 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::target::{
+    generate_hash, Dependencies, Dependency, LanguageTarget, Lockfile, ProgramCommand, Shell,
+    SupportedLanguage,
+};
+use crate::templates::{BuiltinTemplates, TemplateDirs};
+use crate::version::{Operator, Requirement};
+
+const BUILD_TEMPLATE: &str = include_str!("./templates/ruby/Gemfile.tmpl");
+const SOURCE_TEMPLATE: &str = include_str!("./templates/ruby/script.rb.tmpl");
+const SHELL_TEMPLATE: &str = include_str!("./templates/ruby/shell.sh.tmpl");
+
+pub const TEMPLATES: BuiltinTemplates<3> = [
+    ("Gemfile.tmpl", BUILD_TEMPLATE),
+    ("script.rb.tmpl", SOURCE_TEMPLATE),
+    ("shell.sh.tmpl", SHELL_TEMPLATE),
+];
+
+#[derive(Deserialize, Serialize)]
+pub struct Context {
+    pub deps: Dependencies,
+    pub dep_string: String,
+    pub gems: Vec<String>,
+}
+
+pub fn new(
+    deps: Dependencies,
+    shell: bool,
+    templates: &TemplateDirs,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    let mut target = LanguageTarget::new(
+        (
+            String::from("Gemfile"),
+            templates.load("Gemfile.tmpl", BUILD_TEMPLATE)?,
+        ),
+        Context {
+            deps: deps.clone(),
+            dep_string: generate_dep_string(deps.clone())?,
+            gems: generate_gems(deps.clone()),
+        },
+        generate_hash(deps, SupportedLanguage::ruby),
+        vec![ProgramCommand::new(
+            String::from("bundle"),
+            vec![
+                "install".to_string(),
+                "--path".to_string(),
+                "vendor/bundle".to_string(),
+            ],
+        )],
+        if shell {
+            Some(Shell::new(
+                Box::new(|| {
+                    Ok(ProgramCommand::new(
+                        String::from("bundle"),
+                        vec![
+                            "exec".to_string(),
+                            "irb".to_string(),
+                            "-r".to_string(),
+                            "./lib/script.rb".to_string(),
+                        ],
+                    ))
+                }),
+                templates.load("shell.sh.tmpl", SHELL_TEMPLATE)?,
+            ))
+        } else {
+            None
+        },
+        String::from("lib"),
+        vec![(
+            String::from("script.rb"),
+            templates.load("script.rb.tmpl", SOURCE_TEMPLATE)?,
+        )],
+    );
+    target.set_offline_commands(vec![ProgramCommand::new(
+        String::from("bundle"),
+        vec![
+            "install".to_string(),
+            "--local".to_string(),
+            "--path".to_string(),
+            "vendor/bundle".to_string(),
+        ],
+    )]);
+    target.set_lockfile(Lockfile::new(
+        String::from("Gemfile.lock"),
+        ProgramCommand::new(String::from("bundle"), vec!["lock".to_string()]),
+    ));
+    Ok(target)
+}
+
+fn generate_dep_string(deps: Dependencies) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(generate_dep)
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join("\n"))
+}
+
+fn generate_dep(dep: &Dependency) -> anyhow::Result<String> {
+    dep.unsupported("ruby", &["organization"])?;
+
+    let mut parts = vec![format!("gem \"{}\"", dep.name)];
+    if let Some(requirement) = dep.requirement_string(generate_requirement)? {
+        parts.push(requirement);
+    }
+    if let Some(git) = &dep.git {
+        parts.push(format!("git: \"{}\"", git));
+    }
+    if let Some(git_ref) = &dep.git_ref {
+        parts.push(format!("ref: \"{}\"", git_ref));
+    }
+    if let Some(path) = &dep.path {
+        parts.push(format!("path: \"{}\"", path));
+    }
+    if let Some(registry) = &dep.registry {
+        parts.push(format!("source: \"{}\"", registry));
+    }
+    let groups: Vec<String> = dep
+        .only
+        .iter()
+        .map(|env| match env.as_str() {
+            "dev" => String::from(":development"),
+            env => format!(":{}", env),
+        })
+        .collect();
+    match groups.as_slice() {
+        [] => {}
+        [group] => parts.push(format!("group: {}", group)),
+        groups => parts.push(format!("group: [{}]", groups.join(", "))),
+    }
+
+    Ok(parts.join(", "))
+}
+
+/// Bundler takes each comparison as its own string, so this writes them
+/// already quoted.
+pub fn generate_requirement(requirement: &Requirement) -> anyhow::Result<String> {
+    let comparisons = match requirement {
+        Requirement::Compatible(version) => vec![format!("~> {}", version)],
+        Requirement::Exact(version) => vec![format!("= {}", version)],
+        Requirement::Tilde(version) => vec![format!("~> {}", version.extended())],
+        Requirement::Any => vec![String::from(">= 0")],
+        requirement => requirement
+            .comparators()
+            .iter()
+            .map(|(operator, version)| match operator {
+                Operator::Equal => format!("= {}", version),
+                operator => format!("{} {}", operator.symbol(), version),
+            })
+            .collect(),
+    };
+    Ok(comparisons
+        .iter()
+        .map(|comparison| format!("\"{}\"", comparison))
+        .collect::<Vec<String>>()
+        .join(", "))
+}
+
+fn generate_gems(deps: Dependencies) -> Vec<String> {
+    deps.iter().map(|dep| dep.name.clone()).collect()
+}
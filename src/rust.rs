@@ -0,0 +1,132 @@
+use anyhow::anyhow;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::target::{
+    generate_hash, Dependencies, Dependency, LanguageTarget, Lockfile, ProgramCommand, Shell,
+    SupportedLanguage,
+};
+use crate::templates::{self, BuiltinTemplates, TemplateDirs};
+use crate::version::{Operator, Requirement};
+
+const BUILD_TEMPLATE: &str = include_str!("./templates/rust/Cargo.toml.tmpl");
+const SOURCE_TEMPLATE: &str = include_str!("./templates/rust/main.rs.tmpl");
+const SHELL_TEMPLATE: &str = include_str!("./templates/rust/shell.sh.tmpl");
+
+pub const TEMPLATES: BuiltinTemplates<3> = [
+    ("Cargo.toml.tmpl", BUILD_TEMPLATE),
+    ("main.rs.tmpl", SOURCE_TEMPLATE),
+    ("shell.sh.tmpl", SHELL_TEMPLATE),
+];
+
+#[derive(Deserialize, Serialize)]
+pub struct Context {
+    pub deps: Dependencies,
+    pub dep_string: String,
+    pub dev_dep_string: String,
+}
+
+pub fn new(
+    deps: Dependencies,
+    shell: bool,
+    templates: &TemplateDirs,
+) -> anyhow::Result<LanguageTarget<Context>> {
+    let (dev_deps, runtime_deps): (Vec<&Dependency>, Vec<&Dependency>) =
+        deps.iter().partition(|dep| dep.is_dev_only());
+    let dep_string = generate_dep_string(&runtime_deps)?;
+    let dev_dep_string = generate_dep_string(&dev_deps)?;
+    let mut target = LanguageTarget::new(
+        (
+            String::from("Cargo.toml"),
+            templates.load("Cargo.toml.tmpl", BUILD_TEMPLATE)?,
+        ),
+        Context {
+            dep_string,
+            dev_dep_string,
+            deps: deps.clone(),
+        },
+        generate_hash(deps, SupportedLanguage::rust),
+        vec![
+            ProgramCommand::new(String::from("cargo"), vec!["fetch".to_string()]),
+            ProgramCommand::new(String::from("cargo"), vec!["build".to_string()]),
+        ],
+        if shell {
+            // There is no REPL for rust, so the shell script reruns the
+            // project whenever a file changes instead.
+            Some(Shell::new(
+                Box::new(|| Ok(ProgramCommand::new(String::from("./shell.sh"), vec![]))),
+                templates.load("shell.sh.tmpl", SHELL_TEMPLATE)?,
+            ))
+        } else {
+            None
+        },
+        String::from("src"),
+        vec![(
+            String::from("main.rs"),
+            templates.load("main.rs.tmpl", SOURCE_TEMPLATE)?,
+        )],
+    );
+    target.set_offline_commands(vec![ProgramCommand::new(
+        String::from("cargo"),
+        vec!["build".to_string(), "--offline".to_string()],
+    )]);
+    target.set_lockfile(Lockfile::new(
+        String::from("Cargo.lock"),
+        ProgramCommand::new(String::from("cargo"), vec!["generate-lockfile".to_string()]),
+    ));
+    target.add_vars(templates::vars(&[("name", "script")]));
+    Ok(target)
+}
+
+fn generate_dep_string(deps: &[&Dependency]) -> anyhow::Result<String> {
+    Ok(deps
+        .iter()
+        .map(|dep| generate_dep(dep))
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join("\n"))
+}
+
+fn generate_dep(dep: &Dependency) -> anyhow::Result<String> {
+    dep.unsupported("rust", &["organization"])?;
+
+    let requirement = dep.requirement_string(generate_requirement)?;
+    if dep.git.is_none() && dep.path.is_none() && dep.registry.is_none() {
+        return Ok(format!(
+            "{} = \"{}\"",
+            dep.name,
+            requirement.unwrap_or_else(|| String::from("*"))
+        ));
+    }
+
+    let fields = [
+        ("version", requirement.as_ref()),
+        ("git", dep.git.as_ref()),
+        ("rev", dep.git_ref.as_ref()),
+        ("path", dep.path.as_ref()),
+        ("registry", dep.registry.as_ref()),
+    ];
+    let table = fields
+        .iter()
+        .filter_map(|(field, value)| value.map(|value| format!("{} = \"{}\"", field, value)))
+        .collect::<Vec<String>>()
+        .join(", ");
+    Ok(format!("{} = {{ {} }}", dep.name, table))
+}
+
+pub fn generate_requirement(requirement: &Requirement) -> anyhow::Result<String> {
+    Ok(match requirement {
+        Requirement::Compatible(version) => version.to_string(),
+        Requirement::Exact(version) => format!("={}", version.padded()),
+        Requirement::Caret(version) => format!("^{}", version),
+        Requirement::Tilde(version) => format!("~{}", version),
+        Requirement::Range(comparators) => comparators
+            .iter()
+            .map(|(operator, version)| match operator {
+                Operator::Equal => Ok(format!("={}", version.padded())),
+                Operator::NotEqual => Err(anyhow!("cargo has no `!=` for versions")),
+                operator => Ok(format!("{}{}", operator.symbol(), version)),
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?
+            .join(", "),
+        Requirement::Any => String::from("*"),
+    })
+}
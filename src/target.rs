@@ -1,8 +1,11 @@
+use std::collections::HashMap;
 use std::env;
 use std::fmt;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "linux")]
 use std::os::unix::prelude::*;
@@ -12,10 +15,14 @@ use serde::ser::Serialize as SerdeSerialize;
 use serde_derive::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use tinytemplate::TinyTemplate;
-use walkdir::{DirEntry, WalkDir};
 
+use crate::config::Config;
+use crate::custom::CustomTarget;
+use crate::directory::{self, SymlinkMode};
 use crate::opts::Opts;
-use crate::{elixir, node};
+use crate::templates::{self, Prompt, TemplateDirs};
+use crate::version::Requirement;
+use crate::{cache, elixir, mirror, node, python, ruby, rust};
 
 /// The list of supported languages
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -25,54 +32,372 @@ use crate::{elixir, node};
 pub enum SupportedLanguage {
     elixir,
     node,
+    python,
+    ruby,
     rust,
 }
 
+impl FromStr for SupportedLanguage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SupportedLanguage, String> {
+        match s {
+            "elixir" => Ok(SupportedLanguage::elixir),
+            "node" => Ok(SupportedLanguage::node),
+            "python" => Ok(SupportedLanguage::python),
+            "ruby" => Ok(SupportedLanguage::ruby),
+            "rust" => Ok(SupportedLanguage::rust),
+            _ => Err(format!("{} is not a supported language", s)),
+        }
+    }
+}
+
+impl SupportedLanguage {
+    /// The name a package is known by to the language's package manager, so
+    /// that names it treats as the same package compare equal.
+    pub fn normalize_name(&self, name: &str) -> String {
+        match self {
+            SupportedLanguage::elixir | SupportedLanguage::node => name.to_lowercase(),
+            // PEP 503: case, and runs of `-`, `_` and `.`, don't matter.
+            SupportedLanguage::python => name
+                .to_lowercase()
+                .split(['-', '_', '.'])
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<&str>>()
+                .join("-"),
+            // Bundler and cargo both want names exactly as published.
+            SupportedLanguage::ruby | SupportedLanguage::rust => name.to_string(),
+        }
+    }
+}
+
 impl fmt::Display for SupportedLanguage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s: &'static str = match self {
             SupportedLanguage::elixir => "elixir",
             SupportedLanguage::node => "node",
+            SupportedLanguage::python => "python",
+            SupportedLanguage::ruby => "ruby",
             SupportedLanguage::rust => "rust",
         };
         write!(f, "{}", s)
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Dependency {
     pub name: String,
-    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Require exactly `version`, rather than anything compatible with it.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub exact: bool,
+    /// A version requirement in the language's own syntax, used as written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requirement: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<String>,
+    /// The branch, tag or commit to use from `git`.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    /// A local path to the dependency, relative to the generated project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// The hex organization a private elixir package belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    /// The environments, like `dev` or `test`, the dependency is limited to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+    /// The name as the package manager compares it, set by `merge_deps`.
+    /// Duplicates and the build hash go by this, while `name` is written
+    /// into the build file as it was given.
+    #[serde(skip)]
+    pub key: String,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 impl Dependency {
     pub fn new(name: &'static str, version: &'static str) -> Dependency {
         Dependency {
             name: String::from(name),
-            version: String::from(version),
+            version: Some(String::from(version)),
+            ..Dependency::default()
+        }
+    }
+
+    /// The version requirement to write for this dependency: `requirement`
+    /// as written, or else `version` in the language's own `syntax`.
+    pub fn requirement_string<F>(&self, syntax: F) -> anyhow::Result<Option<String>>
+    where
+        F: Fn(&Requirement) -> anyhow::Result<String>,
+    {
+        if let Some(requirement) = &self.requirement {
+            return Ok(Some(requirement.clone()));
+        }
+        match self.version_requirement()? {
+            Some(requirement) => syntax(&requirement)
+                .map(Some)
+                .map_err(|err| anyhow!("Dependency {}: {}", self.name, err)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn version_requirement(&self) -> anyhow::Result<Option<Requirement>> {
+        let version = match &self.version {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        let requirement = Requirement::from_str(version)
+            .map_err(|err| anyhow!("Dependency {} has a bad version: {}", self.name, err))?;
+        match (requirement, self.exact) {
+            (Requirement::Compatible(version), true) => Ok(Some(Requirement::Exact(version))),
+            (_, true) => Err(anyhow!(
+                "Dependency {} can only be `exact` with a plain version",
+                self.name
+            )),
+            (requirement, false) => Ok(Some(requirement)),
+        }
+    }
+
+    pub fn is_dev_only(&self) -> bool {
+        !self.only.is_empty()
+    }
+
+    /// Check for fields that don't make sense together, whatever the
+    /// language.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.git.is_some() && self.path.is_some() {
+            return Err(anyhow!(
+                "Dependency {} can't have both `git` and `path`",
+                self.name
+            ));
+        }
+        if self.git_ref.is_some() && self.git.is_none() {
+            return Err(anyhow!(
+                "Dependency {} has a `ref` but no `git` repository",
+                self.name
+            ));
+        }
+        if self.requirement.is_some() && (self.version.is_some() || self.exact) {
+            return Err(anyhow!(
+                "Dependency {} can't have a `requirement` as well as a `version`",
+                self.name
+            ));
+        }
+        self.version_requirement()?;
+        Ok(())
+    }
+
+    /// Fail for the fields in `fields` that are set, as `language` has no
+    /// way to express them.
+    pub fn unsupported(&self, language: &str, fields: &[&str]) -> anyhow::Result<()> {
+        let set = |field: &str| match field {
+            "git" => self.git.is_some(),
+            "path" => self.path.is_some(),
+            "registry" => self.registry.is_some(),
+            "organization" => self.organization.is_some(),
+            "only" => !self.only.is_empty(),
+            _ => false,
+        };
+        match fields.iter().find(|field| set(field)) {
+            Some(field) => Err(anyhow!(
+                "Dependency {} uses `{}`, which {} targets don't support",
+                self.name,
+                field,
+                language
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn hash_string(&self) -> String {
+        let name = if self.key.is_empty() {
+            &self.name
+        } else {
+            &self.key
+        };
+        let mut hash_string = format!("{},{}", name, self.version.clone().unwrap_or_default());
+        let extras = [
+            ("exact", if self.exact { Some("true") } else { None }),
+            ("requirement", self.requirement.as_deref()),
+            ("git", self.git.as_deref()),
+            ("ref", self.git_ref.as_deref()),
+            ("path", self.path.as_deref()),
+            ("registry", self.registry.as_deref()),
+            ("organization", self.organization.as_deref()),
+        ];
+        for (field, value) in extras.iter() {
+            if let Some(value) = value {
+                hash_string.push_str(&format!(",{}={}", field, value));
+            }
+        }
+        if !self.only.is_empty() {
+            hash_string.push_str(&format!(",only={}", self.only.join("+")));
+        }
+        hash_string
+    }
+}
+
+/// Writes the dependency the way `--dep` takes it, noting where it comes from
+/// if that's not the registry.
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(version) = self.requirement.as_ref().or(self.version.as_ref()) {
+            write!(f, "@{}", version)?;
+        }
+        match (&self.git, &self.path) {
+            (Some(git), _) => write!(f, " (git: {})", git),
+            (None, Some(path)) => write!(f, " (path: {})", path),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Parses `name@version`, as given to `--dep` on the command line.
+impl FromStr for Dependency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dependency, String> {
+        // Split on the last `@`, as scoped npm packages start with one.
+        match s.rsplitn(2, '@').collect::<Vec<&str>>().as_slice() {
+            [version, name] if !name.is_empty() && !version.is_empty() => Ok(Dependency {
+                name: name.to_string(),
+                version: Some(version.to_string()),
+                ..Dependency::default()
+            }),
+            _ => Err(format!("expected name@version, got {}", s)),
         }
     }
 }
 
 pub type Dependencies = Vec<Dependency>;
 
-pub type LanguageTemplate = (&'static str, &'static str);
+/// Combine the named dependency sets with a target's own dependencies.  Sets
+/// are applied in the order they are included, and a dependency replaces any
+/// earlier one of the same name, so a target's own always win.  Names are
+/// compared after putting them through `normalize`.
+pub fn include_deps<F>(
+    sets: &HashMap<String, Dependencies>,
+    include: &[String],
+    deps: Dependencies,
+    normalize: F,
+) -> anyhow::Result<Dependencies>
+where
+    F: Fn(&str) -> String,
+{
+    let mut included = Vec::new();
+    for name in include {
+        let set = sets
+            .get(name)
+            .ok_or_else(|| anyhow!("No dep set named {} in `shelly.yml`", name))?;
+        included.push(set.clone());
+    }
+
+    let mut merged: Dependencies = Vec::new();
+    for dep in included.into_iter().flatten().chain(deps) {
+        let key = normalize(&dep.name);
+        match merged
+            .iter_mut()
+            .find(|existing| normalize(&existing.name) == key)
+        {
+            Some(existing) => *existing = dep,
+            None => merged.push(dep),
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Apply the dependencies added and removed on the command line to a
+/// target's own.  Added dependencies replace any of the same name.  Names
+/// are compared after putting them through `normalize`, and the result is
+/// sorted by that, so the same dependencies always come out the same way.
+pub fn merge_deps<F>(
+    deps: Dependencies,
+    added: Dependencies,
+    removed: Vec<String>,
+    normalize: F,
+) -> anyhow::Result<Dependencies>
+where
+    F: Fn(&str) -> String,
+{
+    let with_key = |dep: Dependency| Dependency {
+        key: normalize(&dep.name),
+        ..dep
+    };
+    let deps: Dependencies = deps.into_iter().map(with_key).collect();
+    let added: Dependencies = added.into_iter().map(with_key).collect();
+    let removed: Vec<String> = removed.iter().map(|name| normalize(name)).collect();
+
+    for (index, dep) in deps.iter().enumerate() {
+        if deps[..index].iter().any(|other| other.key == dep.key) {
+            return Err(anyhow!("{} is listed more than once", dep.name));
+        }
+    }
+    for name in removed.iter() {
+        if !deps.iter().any(|dep| &dep.key == name) {
+            return Err(anyhow!("{} is not a dependency of this target", name));
+        }
+    }
+
+    let mut merged: Dependencies = deps
+        .into_iter()
+        .filter(|dep| !removed.contains(&dep.key))
+        .collect();
+    for dep in added {
+        match merged.iter_mut().find(|existing| existing.key == dep.key) {
+            Some(existing) => *existing = dep,
+            None => merged.push(dep),
+        }
+    }
+
+    for dep in merged.iter() {
+        dep.validate()?;
+    }
+    merged.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok(merged)
+}
+
+pub type LanguageTemplate = (String, String);
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ProgramCommand {
-    command: String,
-    args: Vec<String>,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Environment variables to set for the command, on top of Shelly's own.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
 }
 
 impl ProgramCommand {
     pub fn new(command: String, args: Vec<String>) -> ProgramCommand {
-        ProgramCommand { command, args }
+        ProgramCommand {
+            command,
+            args,
+            env: HashMap::new(),
+        }
+    }
+
+    pub fn with_env(mut self, name: &str, value: &str) -> ProgramCommand {
+        self.env.insert(name.to_string(), value.to_string());
+        self
     }
 
     pub fn run(&self) -> anyhow::Result<()> {
         let mut cmd = self.get_command()?;
         let mut child = cmd.spawn()?;
-        let _ = child.wait()?;
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(anyhow!("`{}` exited with {}", self.command, status));
+        }
         Ok(())
     }
 
@@ -84,10 +409,42 @@ impl ProgramCommand {
         Ok(())
     }
 
+    /// Run the command without showing its output, only reporting whether it
+    /// succeeded.
+    pub fn run_quietly(&self) -> bool {
+        Command::new(&self.command)
+            .args(&self.args)
+            .envs(&self.env)
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    /// Run the command without showing its output, unless it fails, when the
+    /// error includes what it wrote to stderr.
+    pub fn run_capturing(&self) -> anyhow::Result<()> {
+        let output = Command::new(&self.command)
+            .args(&self.args)
+            .envs(&self.env)
+            .output()
+            .map_err(|err| anyhow!("Failed to run `{}`: {}", self.command, err))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "`{} {}` exited with {}:\n{}",
+                self.command,
+                self.args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ));
+        }
+        Ok(())
+    }
+
     fn get_command(&self) -> anyhow::Result<Command> {
         let stdout = os_pipe::dup_stdout()?;
         let mut cmd = Command::new(self.command.clone());
         cmd.args(self.args.clone());
+        cmd.envs(&self.env);
         cmd.stdout(stdout);
 
         Ok(cmd)
@@ -98,11 +455,11 @@ type GetShellArgs = Box<dyn Fn() -> anyhow::Result<ProgramCommand>>;
 
 pub struct Shell {
     get_command: GetShellArgs,
-    template: &'static str,
+    template: String,
 }
 
 impl Shell {
-    pub fn new(get_command: GetShellArgs, template: &'static str) -> Shell {
+    pub fn new(get_command: GetShellArgs, template: String) -> Shell {
         Shell {
             get_command,
             template,
@@ -125,14 +482,16 @@ pub struct Templates {
     build_template: Template,
     source_templates: Vec<Template>,
     shell_template: Option<Template>,
+    extra_files: Vec<Template>,
 }
 
-impl<'a> Templates {
+impl Templates {
     pub fn new(build_template: Template) -> Templates {
         Templates {
             build_template,
             source_templates: Vec::new(),
             shell_template: None,
+            extra_files: Vec::new(),
         }
     }
 
@@ -143,75 +502,425 @@ impl<'a> Templates {
     pub fn add_shell_template(&mut self, template: String) {
         self.shell_template = Some(Template::new(String::from("shell.sh"), template));
     }
+
+    pub fn add_extra_file(&mut self, path: String, template: String) {
+        self.extra_files.push(Template { path, template });
+    }
+
+    /// Every file these write, relative to the project.
+    fn paths(&self) -> Vec<String> {
+        std::iter::once(&self.build_template)
+            .chain(self.source_templates.iter())
+            .chain(self.shell_template.iter())
+            .chain(self.extra_files.iter())
+            .map(|template| template.path.clone())
+            .collect()
+    }
+}
+
+/// A file to add to every generated project, given either inline as
+/// `content` or as a `template` file relative to `shelly.yml`.  Both are
+/// rendered with the same context as the language's own templates.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExtraFile {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+}
+
+/// Whether `path` only ever goes down into the folder it's relative to,
+/// without `..`, a root or a prefix.
+pub fn stays_inside(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+impl ExtraFile {
+    fn read(&self, config_dir: &Path) -> anyhow::Result<LanguageTemplate> {
+        if !stays_inside(Path::new(&self.path)) {
+            return Err(anyhow!(
+                "Extra file path must be relative to the project and inside it, got {}",
+                self.path
+            ));
+        }
+        let template = match (&self.content, &self.template) {
+            (Some(content), None) => content.clone(),
+            (None, Some(template)) => fs::read_to_string(config_dir.join(template))
+                .map_err(|err| anyhow!("Failed to read template {}: {}", template, err))?,
+            _ => {
+                return Err(anyhow!(
+                    "Extra file {} needs exactly one of `content` or `template`",
+                    self.path
+                ))
+            }
+        };
+        Ok((self.path.clone(), template))
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DefaultTarget {
     pub language: Option<SupportedLanguage>,
     pub name: String,
     pub deps: Option<Dependencies>,
+    /// Names of `dep_sets` whose dependencies this target also uses.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<node::Runtime>,
+    /// A folder of templates that take precedence over the user's and the
+    /// built-in ones for this target.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub templates: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_files: Vec<ExtraFile>,
+    /// Variables for the templates, available as `vars.<name>`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub vars: HashMap<String, String>,
+    /// How to ask for variables the templates use but nothing has set.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub prompts: HashMap<String, Prompt>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitMode>,
 }
 
 impl DefaultTarget {
     pub fn new(name: String, language: SupportedLanguage, deps: Dependencies) -> DefaultTarget {
         DefaultTarget {
             deps: Some(deps),
+            include: Vec::new(),
             language: Some(language),
             name,
+            runtime: None,
+            templates: None,
+            extra_files: Vec::new(),
+            vars: HashMap::new(),
+            prompts: HashMap::new(),
+            git: None,
         }
     }
 
-    pub fn execute(&self, opts: Opts, build_dir: String) -> anyhow::Result<()> {
-        match self.language {
-            Some(SupportedLanguage::elixir) => {
-                let target = elixir::new(self.deps.clone().unwrap(), opts.get_shell());
-                let cached = target.is_cached(self.name.clone(), build_dir);
-                match (opts.get_no_cache(), cached) {
-                    (true, _) | (false, false) => {
-                        target.write_project(opts.get_path())?;
-                        // target.write_hash(self.name.clone(), build_dir)?;
-                        target.run()?;
-                        Ok(())
-                    }
-                    _ => {
-                        // copy from hashed entry!
-                        Ok(())
-                    }
-                }
+    pub fn execute(
+        &self,
+        opts: Opts,
+        build_dir: String,
+        cache: bool,
+        config_dir: &Path,
+    ) -> anyhow::Result<()> {
+        let cache = cache && !opts.get_no_cache();
+        self.run(&opts, build_dir, config_dir, Action::Build { cache })
+    }
+
+    /// Resolve the target's dependencies into a lockfile in `build_dir`.
+    pub fn lock(&self, opts: Opts, build_dir: String, config_dir: &Path) -> anyhow::Result<()> {
+        self.run(&opts, build_dir, config_dir, Action::Lock)
+    }
+
+    /// Build the target into the cache in `build_dir`, without a project.
+    pub fn warm(&self, opts: &Opts, build_dir: String, config_dir: &Path) -> anyhow::Result<()> {
+        self.run(opts, build_dir, config_dir, Action::Warm)
+    }
+
+    fn run(
+        &self,
+        opts: &Opts,
+        build_dir: String,
+        config_dir: &Path,
+        action: Action,
+    ) -> anyhow::Result<()> {
+        let language = self
+            .language
+            .clone()
+            .ok_or_else(|| anyhow!("No language specified for target {}", self.name))?;
+        let templates = TemplateDirs::new(
+            self.templates.as_ref().map(|dir| config_dir.join(dir)),
+            templates::user_dir(&language)?,
+        );
+        let deps = merge_deps(
+            self.deps.clone().unwrap_or_default(),
+            opts.get_deps(),
+            opts.get_without(),
+            |name| language.normalize_name(name),
+        )?;
+        let extra_files = self
+            .extra_files
+            .iter()
+            .map(|file| file.read(config_dir))
+            .collect::<anyhow::Result<Vec<LanguageTemplate>>>()?;
+        let shell = opts.get_shell() && matches!(action, Action::Build { .. });
+        let described_deps = deps.clone();
+        match language {
+            SupportedLanguage::elixir => {
+                let target = elixir::new(deps, shell, &templates)?;
+                self.generate(
+                    target,
+                    &described_deps,
+                    extra_files,
+                    opts,
+                    build_dir,
+                    action,
+                )
             }
-            Some(SupportedLanguage::node) => {
-                let target = node::new(self.deps.clone().unwrap(), opts.get_shell());
-                let cached = target.is_cached(self.name.clone(), build_dir);
-                match (opts.get_no_cache(), cached) {
-                    (true, _) | (false, false) => {
-                        target.write_project(opts.get_path())?;
-                        // target.write_hash(self.name.clone(), build_dir)?;
-                        target.run()?;
-                        Ok(())
-                    }
-                    _ => {
-                        // copy from hashed entry!
-                        Ok(())
-                    }
-                }
+            SupportedLanguage::node => {
+                let runtime = self.runtime.clone().unwrap_or_default();
+                let target = node::new(deps, shell, runtime, &templates)?;
+                self.generate(
+                    target,
+                    &described_deps,
+                    extra_files,
+                    opts,
+                    build_dir,
+                    action,
+                )
             }
-            _ => Err(anyhow!(
-                "{} is not a supported language",
-                self.language.clone().unwrap()
-            )),
+            SupportedLanguage::python => {
+                let target = python::new(deps, shell, &templates)?;
+                self.generate(
+                    target,
+                    &described_deps,
+                    extra_files,
+                    opts,
+                    build_dir,
+                    action,
+                )
+            }
+            SupportedLanguage::ruby => {
+                let target = ruby::new(deps, shell, &templates)?;
+                self.generate(
+                    target,
+                    &described_deps,
+                    extra_files,
+                    opts,
+                    build_dir,
+                    action,
+                )
+            }
+            SupportedLanguage::rust => {
+                let target = rust::new(deps, shell, &templates)?;
+                self.generate(
+                    target,
+                    &described_deps,
+                    extra_files,
+                    opts,
+                    build_dir,
+                    action,
+                )
+            }
+        }
+    }
+
+    fn generate<T>(
+        &self,
+        mut target: LanguageTarget<T>,
+        deps: &[Dependency],
+        extra_files: Vec<LanguageTemplate>,
+        opts: &Opts,
+        build_dir: String,
+        action: Action,
+    ) -> anyhow::Result<()>
+    where
+        T: SerdeSerialize,
+    {
+        target.add_extra_files(extra_files);
+        target.add_vars(
+            self.vars
+                .clone()
+                .into_iter()
+                .chain(opts.get_vars())
+                .collect(),
+        );
+        target.set_prompts(self.prompts.clone(), !opts.get_no_input());
+        target.set_refresh_lock(opts.get_refresh_lock());
+        target.describe(self.language_label(), deps);
+        target.set_offline(opts.get_offline());
+        target.set_git(self.git.unwrap_or_default());
+        match action {
+            Action::Build { cache } => {
+                target.build(self.name.clone(), opts.get_path(), build_dir, cache)
+            }
+            Action::Lock => target.lock(build_dir),
+            Action::Warm => target.warm(self.name.clone(), build_dir),
+        }
+    }
+
+    /// The language as shown in the cache listing, with the runtime for node.
+    fn language_label(&self) -> String {
+        match (&self.language, &self.runtime) {
+            (Some(SupportedLanguage::node), Some(runtime)) => format!("node ({})", runtime),
+            (Some(language), _) => language.to_string(),
+            (None, _) => String::new(),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// What to do with a target's project once it's been put together.
+#[derive(Clone, Copy)]
+enum Action {
+    Build { cache: bool },
+    Lock,
+    Warm,
+}
+
+/// What to do with the git repository of a generated project.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[allow(non_camel_case_types)]
+pub enum GitMode {
+    /// Start a new repository whose first commit is the generated files.
+    fresh,
+    /// Leave whatever repository the project came with, if any.
+    #[default]
+    keep,
+    /// Leave no repository.
+    none,
+}
+
+impl GitMode {
+    /// Set up the repository in `project_path`.  A `fresh` one commits
+    /// `files`, or everything not ignored without a list.  Only a `.git` that
+    /// shelly `cloned` is replaced; any other is left alone and is an error.
+    pub fn apply(
+        &self,
+        project_path: &Path,
+        files: Option<Vec<String>>,
+        cloned: bool,
+    ) -> anyhow::Result<()> {
+        let git_dir = project_path.join(".git");
+        if let GitMode::keep = self {
+            return Ok(());
+        }
+        if git_dir.exists() && !cloned {
+            return Err(anyhow!(
+                "{:?} already has a git repository, which `git: {:?}` would replace",
+                project_path,
+                self
+            ));
+        }
+        if git_dir.exists() {
+            fs::remove_dir_all(&git_dir)
+                .map_err(|err| anyhow!("Failed to remove {:?}: {}", git_dir, err))?;
+        }
+        if let GitMode::none = self {
+            return Ok(());
+        }
+
+        let path = project_path.to_string_lossy().to_string();
+        let git = |args: Vec<String>| ProgramCommand::new(String::from("git"), args).run();
+        let args = |args: &[&str]| {
+            ["-C", &path]
+                .iter()
+                .chain(args)
+                .map(|arg| arg.to_string())
+                .collect::<Vec<String>>()
+        };
+        git(args(&["init", "--quiet"]))?;
+        let mut add = args(&["add", "--"]);
+        match files {
+            Some(files) => add.extend(files),
+            None => add.push(String::from(".")),
+        }
+        git(add)?;
+        // Committing needs an identity, so fall back to one when git has
+        // none configured rather than leaving the files uncommitted.
+        let mut commit = args(&[]);
+        if !ProgramCommand::new(String::from("git"), args(&["config", "user.email"])).run_quietly()
+        {
+            commit.extend(
+                [
+                    "-c",
+                    "user.name=shelly",
+                    "-c",
+                    "user.email=shelly@localhost",
+                ]
+                .iter()
+                .map(|arg| arg.to_string()),
+            );
+        }
+        commit.extend(
+            ["commit", "--quiet", "-m", "Generated by shelly"]
+                .iter()
+                .map(|arg| arg.to_string()),
+        );
+        git(commit).map_err(|err| anyhow!("Failed to commit the generated project: {}", err))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RemoteTarget {
     build_args: Vec<String>,
     build_command: String,
     path: String,
     shell: Option<ProgramCommand>,
+    /// A branch, tag or full commit SHA to check out instead of the default
+    /// branch.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    git_ref: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    depth: Option<u32>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    submodules: bool,
+    /// Only this directory of the repo becomes the project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    subdir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git: Option<GitMode>,
+    /// Globs of paths a `Directory` target never copies, in `.gitignore`
+    /// syntax, defaulting to `.git` and `node_modules`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exclude: Option<Vec<String>>,
+    /// Globs of paths a `Directory` target copies even when an ignore file
+    /// skips them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlinks: Option<SymlinkMode>,
+}
+
+fn hash_parts<I>(parts: I) -> String
+where
+    I: Iterator<Item = String>,
+{
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+    format!("{:X}", hasher.finalize())
 }
 
 impl RemoteTarget {
+    /// Everything apart from the commit that changes what a build of the
+    /// repo leaves behind.
+    fn settings(&self) -> Vec<String> {
+        vec![
+            self.git_ref.clone().unwrap_or_default(),
+            self.depth
+                .map(|depth| depth.to_string())
+                .unwrap_or_default(),
+            self.subdir.clone().unwrap_or_default(),
+            self.submodules.to_string(),
+            format!("{:?}", self.git.unwrap_or_default()),
+            self.build_command.clone(),
+        ]
+        .into_iter()
+        .chain(self.build_args.iter().cloned())
+        .collect()
+    }
+
+    /// The cache key for a build of the repo at `commit`.
+    fn hash(&self, commit: &str) -> String {
+        hash_parts(std::iter::once(commit.to_string()).chain(self.settings()))
+    }
+
+    /// What a build of the repo at any commit is checked against, when
+    /// there's no mirror to find the commit in.
+    fn settings_hash(&self) -> String {
+        hash_parts(std::iter::once(self.path.clone()).chain(self.settings()))
+    }
+
     pub fn new(
         path: String,
         build_command: String,
@@ -223,34 +932,55 @@ impl RemoteTarget {
             build_command,
             path,
             shell,
+            git_ref: None,
+            depth: None,
+            submodules: false,
+            subdir: None,
+            git: None,
+            exclude: None,
+            include: Vec::new(),
+            symlinks: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Target {
     Internal(DefaultTarget),
+    Custom(Box<CustomTarget>),
     Directory(RemoteTarget),
     Repo(RemoteTarget),
 }
 
-pub fn generate_hash(deps: Dependencies, language: SupportedLanguage) -> String {
+pub fn generate_hash<L>(deps: Dependencies, language: L) -> String
+where
+    L: fmt::Display,
+{
     let dep_string = deps
         .iter()
-        .map(|dep| format!("{},{}", dep.name, dep.version))
+        .map(|dep| dep.hash_string())
         .collect::<Vec<String>>()
         .join(":");
 
     let mut hasher = Sha256::new();
-    let bytes = [
-        language.to_string().as_str().as_bytes(),
-        dep_string.as_bytes(),
-    ]
-    .concat();
+    let bytes = [language.to_string().as_bytes(), dep_string.as_bytes()].concat();
     hasher.update(&bytes);
     format!("{:X}", hasher.finalize())
 }
 
+/// The file a package manager records resolved versions in, and the command
+/// that resolves them without installing anything.
+pub struct Lockfile {
+    pub file: String,
+    pub command: ProgramCommand,
+}
+
+impl Lockfile {
+    pub fn new(file: String, command: ProgramCommand) -> Lockfile {
+        Lockfile { file, command }
+    }
+}
+
 pub struct LanguageTarget<T>
 where
     T: SerdeSerialize,
@@ -258,10 +988,34 @@ where
     build_template: LanguageTemplate,
     context: T,
     hash: String,
-    run_command: ProgramCommand,
+    lockfile: Option<Lockfile>,
+    refresh_lock: bool,
+    language: String,
+    deps: Vec<String>,
+    /// Each dependency's name as written, and as the package manager
+    /// compares it.
+    dep_names: Vec<(String, String)>,
+    offline: bool,
+    offline_commands: Option<Vec<ProgramCommand>>,
+    relocate: Vec<String>,
+    git: GitMode,
+    run_commands: Vec<ProgramCommand>,
+    extra_files: Vec<LanguageTemplate>,
     shell: Option<Shell>,
-    source_directory: &'static str,
+    source_directory: String,
     source_templates: Vec<LanguageTemplate>,
+    vars: HashMap<String, String>,
+    prompts: HashMap<String, Prompt>,
+    interactive: bool,
+}
+
+/// What templates are rendered with: the language's own context, plus any
+/// user variables under `vars`.
+#[derive(Serialize)]
+struct RenderContext<'a, T> {
+    #[serde(flatten)]
+    context: &'a T,
+    vars: &'a HashMap<String, String>,
 }
 
 impl<T> LanguageTarget<T>
@@ -272,73 +1026,445 @@ where
         build_template: LanguageTemplate,
         context: T,
         hash: String,
-        run_command: ProgramCommand,
+        run_commands: Vec<ProgramCommand>,
         shell: Option<Shell>,
-        source_directory: &'static str,
+        source_directory: String,
         source_templates: Vec<LanguageTemplate>,
     ) -> LanguageTarget<T> {
         LanguageTarget {
             build_template,
             context,
+            extra_files: Vec::new(),
             hash,
-            run_command,
+            lockfile: None,
+            refresh_lock: false,
+            language: String::new(),
+            deps: Vec::new(),
+            dep_names: Vec::new(),
+            offline: false,
+            offline_commands: None,
+            relocate: Vec::new(),
+            git: GitMode::default(),
+            run_commands,
             shell,
             source_directory,
             source_templates,
+            vars: HashMap::new(),
+            prompts: HashMap::new(),
+            interactive: false,
         }
     }
 
-    fn hash_path(&self, name: String, build_dir: String) -> String {
-        let file_name = format!("{}.sha1", name);
-        String::from(
-            Path::new(build_dir.as_str())
-                .join(file_name.as_str())
-                .to_str()
-                .expect("Bad sha1 file name generated"),
-        )
+    /// Ask for any variables the templates use that haven't been set, using
+    /// `prompts` for their descriptions and defaults.  Without `interactive`,
+    /// only the defaults are used.
+    pub fn set_prompts(&mut self, prompts: HashMap<String, Prompt>, interactive: bool) {
+        self.prompts = prompts;
+        self.interactive = interactive;
     }
 
-    fn is_cached(&self, name: String, build_dir: String) -> bool {
-        let hash_path = self.hash_path(name, build_dir);
-        fs::read_to_string(hash_path).map_or(false, |hash| hash == self.hash);
-        false
+    /// Keep the lockfile from each install next to the build cache, and reuse
+    /// it for later installs of the same dependencies.
+    pub fn set_lockfile(&mut self, lockfile: Lockfile) {
+        self.lockfile = Some(lockfile);
+    }
+
+    /// Resolve dependencies again instead of reusing a stored lockfile or
+    /// cached build.
+    pub fn set_refresh_lock(&mut self, refresh_lock: bool) {
+        self.refresh_lock = refresh_lock;
+    }
+
+    /// What the build is of, for the cache's metadata and for listing what a
+    /// build that can't be done offline needs.
+    pub fn describe(&mut self, language: String, deps: &[Dependency]) {
+        self.language = language;
+        self.deps = deps.iter().map(Dependency::to_string).collect();
+        self.dep_names = deps
+            .iter()
+            .map(|dep| (dep.name.clone(), dep.key.clone()))
+            .collect();
+    }
+
+    /// Only build from the cache, or failing that from the package manager's
+    /// local cache.
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// How to install using only the package manager's local cache, for
+    /// languages that can.
+    pub fn set_offline_commands(&mut self, commands: Vec<ProgramCommand>) {
+        self.offline_commands = Some(commands);
+    }
+
+    /// Folders, relative to the project, whose files hard-code the path they
+    /// were installed at, and so need it rewritten when restored from the
+    /// cache into another folder.
+    pub fn set_relocate(&mut self, dirs: Vec<String>) {
+        self.relocate = dirs;
+    }
+
+    /// What repository the project gets once it's built.
+    pub fn set_git(&mut self, git: GitMode) {
+        self.git = git;
+    }
+
+    /// Set template variables, replacing any earlier value for the same name.
+    pub fn add_vars(&mut self, vars: HashMap<String, String>) {
+        self.vars.extend(vars);
+    }
+
+    /// Add files to write into the project, relative to its root, alongside
+    /// the language's own templates.
+    pub fn add_extra_files(&mut self, mut files: Vec<LanguageTemplate>) {
+        self.extra_files.append(&mut files);
+    }
+
+    /// Write the project to `path` and install its dependencies, or restore
+    /// them from the build cache, and then drop into the shell if requested.
+    pub fn build(
+        mut self,
+        name: String,
+        path: String,
+        build_dir: String,
+        cache: bool,
+    ) -> anyhow::Result<()> {
+        // `write_project` changes into the project folder, so pin both paths
+        // down before anything relative stops meaning what the user meant.
+        let cwd = env::current_dir()?;
+        let project_path = cwd.join(path);
+        let build_dir = cwd.join(build_dir);
+        let rendered = self.generate_templates()?;
+        self.hash = self.hash_with_build_file(&rendered);
+
+        if !cache {
+            self.install_project(&rendered, &project_path, &build_dir)?;
+        } else {
+            if self.refresh_lock || !self.is_cached(&build_dir) {
+                self.build_into_cache(name, &rendered, &build_dir, &cwd)?;
+            } else {
+                cache::touch(&build_dir, &self.hash)?;
+            }
+            println!("Restoring cached build...");
+            fs::create_dir_all(&project_path)
+                .map_err(|err| anyhow!("Failed to create project folder: {}", err))?;
+            cache::restore(&build_dir, &self.hash, &project_path)?;
+            let restored_at = project_path.canonicalize()?;
+            for dir in &self.relocate {
+                cache::relocate(
+                    &project_path.join(dir),
+                    cache::PROJECT_PATH,
+                    &restored_at.to_string_lossy(),
+                )?;
+            }
+            self.write_project(&rendered, project_path.clone())?;
+        }
+
+        // Only what shelly wrote is committed, leaving out the installed
+        // dependencies.
+        let mut files = rendered.paths();
+        if let Some(lockfile) = &self.lockfile {
+            if project_path.join(&lockfile.file).exists() {
+                files.push(lockfile.file.clone());
+            }
+        }
+        self.git.apply(&project_path, Some(files), false)?;
+
+        self.shell()
     }
 
-    /* fn write_hash(&self, name: String, build_dir: String) -> anyhow::Result<()> {
+    /// Build the project in a scratch folder inside `build_dir` and store it
+    /// in the cache, unless it's already there.
+    pub fn warm(mut self, name: String, build_dir: String) -> anyhow::Result<()> {
+        let cwd = env::current_dir()?;
+        let build_dir = cwd.join(build_dir);
+        let rendered = self.generate_templates()?;
+        self.hash = self.hash_with_build_file(&rendered);
+
+        if !self.refresh_lock && self.is_cached(&build_dir) {
+            println!("Already cached as {}", self.hash);
+            return self.write_hash(name, &build_dir);
+        }
+
+        self.build_into_cache(name, &rendered, &build_dir, &cwd)
+    }
+
+    /// Install the project in a scratch folder inside `build_dir` and store
+    /// that, so nothing else in the project's folder ends up in the cache.
+    /// Rendered files other than the build file are left out, as they're
+    /// written fresh into every project.
+    fn build_into_cache(
+        &self,
+        name: String,
+        rendered: &Templates,
+        build_dir: &Path,
+        cwd: &Path,
+    ) -> anyhow::Result<()> {
+        let scratch = build_dir.join(format!("{}.building", self.hash));
+        if scratch.exists() {
+            fs::remove_dir_all(&scratch)?;
+        }
+        let built = self
+            .install_project(rendered, &scratch, build_dir)
+            .and_then(|_| {
+                for path in rendered.paths().iter().skip(1) {
+                    let path = scratch.join(path);
+                    if path.is_file() {
+                        fs::remove_file(&path)?;
+                    }
+                }
+                let built_at = scratch.canonicalize()?;
+                for dir in &self.relocate {
+                    cache::relocate(
+                        &scratch.join(dir),
+                        &built_at.to_string_lossy(),
+                        cache::PROJECT_PATH,
+                    )?;
+                }
+                self.store_build(name, &scratch, build_dir)
+            });
+        env::set_current_dir(cwd)?;
+        fs::remove_dir_all(&scratch)
+            .map_err(|err| anyhow!("Failed to clean up {:?}: {}", scratch, err))?;
+        built
+    }
+
+    /// Write the project and install its dependencies, reusing and then
+    /// keeping its lockfile.
+    fn install_project(
+        &self,
+        rendered: &Templates,
+        project_path: &Path,
+        build_dir: &Path,
+    ) -> anyhow::Result<()> {
+        self.write_project(rendered, project_path.to_path_buf())?;
+        self.restore_lockfile(project_path, build_dir)?;
+        if self.offline {
+            self.install_offline()?;
+        } else {
+            self.install()?;
+        }
+        if let Err(err) = self.store_lockfile(project_path, build_dir) {
+            println!("Failed to store lockfile: {}", err);
+        }
+        Ok(())
+    }
+
+    fn store_build(
+        &self,
+        name: String,
+        project_path: &Path,
+        build_dir: &Path,
+    ) -> anyhow::Result<()> {
+        cache::store(project_path, build_dir, &self.hash)?;
+        cache::write_metadata(
+            build_dir,
+            &self.hash,
+            &cache::Metadata::new(name.clone(), self.language.clone(), self.deps.clone()),
+        )?;
+        self.write_hash(name, build_dir)
+    }
+
+    /// Resolve the dependencies into a lockfile in the build cache, without
+    /// leaving a project behind.
+    pub fn lock(mut self, build_dir: String) -> anyhow::Result<()> {
+        let lockfile = self.lockfile.as_ref().ok_or_else(|| {
+            anyhow!(
+                "{} targets have no lockfile to resolve into; pin versions with \
+                     `exact: true` or `=` in `deps` instead",
+                self.language
+            )
+        })?;
+        if self.offline {
+            return Err(anyhow!("Dependencies can't be resolved offline"));
+        }
+        let cwd = env::current_dir()?;
+        let build_dir = cwd.join(build_dir);
+        let rendered = self.generate_templates()?;
+        self.hash = self.hash_with_build_file(&rendered);
+        let scratch = build_dir.join(format!("{}.locking", self.hash));
+
+        if scratch.exists() {
+            fs::remove_dir_all(&scratch)?;
+        }
+        self.write_project(&rendered, scratch.clone())?;
+        let locked = lockfile
+            .command
+            .run()
+            .and_then(|_| self.store_lockfile(&scratch, &build_dir));
+        env::set_current_dir(&cwd)?;
+        fs::remove_dir_all(&scratch)
+            .map_err(|err| anyhow!("Failed to clean up {:?}: {}", scratch, err))?;
+        locked?;
+
+        println!("Wrote {:?}", cache::lockfile_path(&build_dir, &self.hash));
+        Ok(())
+    }
+
+    /// Without a cached build, the package manager can still install from its
+    /// own cache.  Its output is only shown if that fails, as whatever it
+    /// couldn't find has to be fetched once back online.
+    fn install_offline(&self) -> anyhow::Result<()> {
+        let deps = if self.deps.is_empty() {
+            String::from("  (none)")
+        } else {
+            self.deps
+                .iter()
+                .map(|dep| format!("  {}", dep))
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+        let reason = match &self.offline_commands {
+            Some(commands) => {
+                println!("Installing from local package caches...");
+                match commands.iter().try_for_each(ProgramCommand::run_capturing) {
+                    Ok(()) => return Ok(()),
+                    Err(err) => format!("it could not be installed offline: {}", err),
+                }
+            }
+            None => format!(
+                "{} targets can't install from a local package cache",
+                self.language
+            ),
+        };
+        Err(anyhow!(
+            "Can't build offline: there is no cached build for {}, and {}\n\
+             Its dependencies are:\n{}\n\
+             Run it once online to cache it.",
+            self.hash,
+            reason,
+            deps
+        ))
+    }
+
+    fn restore_lockfile(&self, project_path: &Path, build_dir: &Path) -> anyhow::Result<()> {
+        match &self.lockfile {
+            Some(lockfile) if !self.refresh_lock => {
+                if cache::restore_lockfile(
+                    build_dir,
+                    &self.hash,
+                    &project_path.join(&lockfile.file),
+                )? {
+                    println!("Using stored {}...", lockfile.file);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn store_lockfile(&self, project_path: &Path, build_dir: &Path) -> anyhow::Result<()> {
+        match &self.lockfile {
+            Some(lockfile) => {
+                cache::store_lockfile(&project_path.join(&lockfile.file), build_dir, &self.hash)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// What gets installed also depends on the rendered build file, which
+    /// templates and variables can change without any dependency changing.
+    /// Names are hashed as the package manager compares them, so spelling a
+    /// dependency differently reuses its build.
+    fn hash_with_build_file(&self, rendered: &Templates) -> String {
+        let mut build_file = rendered.build_template.template.clone();
+        for (name, key) in self.dep_names.iter() {
+            if !key.is_empty() && name != key {
+                build_file = build_file.replace(name.as_str(), key);
+            }
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash.as_bytes());
+        hasher.update(templates::VERSION.to_string().as_bytes());
+        hasher.update(build_file.as_bytes());
+        format!("{:X}", hasher.finalize())
+    }
+
+    fn hash_path(&self, name: String, build_dir: &Path) -> PathBuf {
+        build_dir.join(format!("{}.sha1", name))
+    }
+
+    fn is_cached(&self, build_dir: &Path) -> bool {
+        cache::entry_path(build_dir, &self.hash).is_dir()
+    }
+
+    /// Record the hash of the latest build for `name`, for easier diffing of
+    /// what changed between builds of the same target.
+    fn write_hash(&self, name: String, build_dir: &Path) -> anyhow::Result<()> {
         let hash_path = self.hash_path(name, build_dir);
         fs::write(hash_path, self.hash.clone())
             .map_err(|err| anyhow!("Failed to write hash: {}", err))
-    } */
+    }
+
+    /// Fill in every variable the templates reference but that hasn't been
+    /// set, from the user or from the prompt's default.
+    fn resolve_vars(&self) -> anyhow::Result<HashMap<String, String>> {
+        let mut vars = self.vars.clone();
+
+        let sources = std::iter::once(&self.build_template)
+            .chain(self.source_templates.iter())
+            .chain(self.extra_files.iter())
+            .map(|(_, template)| template.as_str())
+            .chain(self.shell.iter().map(|shell| shell.template.as_str()));
+
+        for source in sources {
+            for name in templates::referenced_vars(source) {
+                if vars.contains_key(&name) {
+                    continue;
+                }
+                let value = templates::prompt(&name, self.prompts.get(&name), self.interactive)?;
+                vars.insert(name, value);
+            }
+        }
+
+        Ok(vars)
+    }
 
     fn generate_templates(&self) -> anyhow::Result<Templates> {
         let mut template = TinyTemplate::new();
 
-        template.add_template(self.build_template.0, self.build_template.1)?;
+        template.add_template(&self.build_template.0, &self.build_template.1)?;
 
         for (name, template_string) in self.source_templates.iter() {
             template.add_template(name, template_string)?;
         }
 
         if let Some(shell) = &self.shell {
-            template.add_template("shell.sh", shell.template)?;
+            template.add_template("shell.sh", &shell.template)?;
+        }
+
+        // Extra files get their own namespace, as their paths can clash with
+        // the names of the source templates.
+        let extra_names: Vec<String> = self
+            .extra_files
+            .iter()
+            .map(|(path, _)| format!("extra:{}", path))
+            .collect();
+        for (name, (_, template_string)) in extra_names.iter().zip(self.extra_files.iter()) {
+            template.add_template(name, template_string)?;
         }
 
         template.set_default_formatter(&tinytemplate::format_unescaped);
 
+        let vars = self.resolve_vars()?;
+        let context = RenderContext {
+            context: &self.context,
+            vars: &vars,
+        };
+
         let build_template = template
-            .render(self.build_template.0, &self.context)
+            .render(&self.build_template.0, &context)
             .map_err(|err| anyhow!("Failed to render {}: {}", self.build_template.0, err))?;
 
-        let mut templates = Templates::new(Template::new(
-            String::from(self.build_template.0),
-            build_template,
-        ));
+        let mut templates =
+            Templates::new(Template::new(self.build_template.0.clone(), build_template));
 
         for (name, _template) in self.source_templates.iter() {
             let source_template = template
-                .render(name, &self.context)
+                .render(name, &context)
                 .map_err(|err| anyhow!("Failed to render source {}: {}", name, err))?;
-            let source_path: String = PathBuf::from(self.source_directory)
+            let source_path: String = PathBuf::from(&self.source_directory)
                 .join(name)
                 .to_str()
                 .unwrap()
@@ -348,31 +1474,34 @@ where
 
         if self.shell.is_some() {
             let shell_template = template
-                .render("shell.sh", &self.context)
+                .render("shell.sh", &context)
                 .map_err(|err| anyhow!("Failed to render shell template: {}", err))?;
             templates.add_shell_template(shell_template);
         }
 
+        for (name, (path, _)) in extra_names.iter().zip(self.extra_files.iter()) {
+            let extra_file = template
+                .render(name, &context)
+                .map_err(|err| anyhow!("Failed to render extra file {}: {}", path, err))?;
+            templates.add_extra_file(path.clone(), extra_file);
+        }
+
         Ok(templates)
     }
 
-    pub fn write_project(&self, path: String) -> anyhow::Result<()> {
+    pub fn write_project(&self, templates: &Templates, folder_path: PathBuf) -> anyhow::Result<()> {
         println!("Generating project...");
 
-        let folder_path = PathBuf::from(path);
-
-        let templates = self.generate_templates()?;
-
         fs::create_dir_all(folder_path.clone())
             .map_err(|err| anyhow!("Failed to create project folder: {}", err))?;
         env::set_current_dir(folder_path)
             .map_err(|err| anyhow!("Failed to change to project directory: {}", err))?;
         fs::write(
-            templates.build_template.path,
-            templates.build_template.template,
+            &templates.build_template.path,
+            &templates.build_template.template,
         )
         .map_err(|err| anyhow!("Failed to write build template: {}", err))?;
-        fs::create_dir(PathBuf::from(self.source_directory))
+        fs::create_dir_all(PathBuf::from(&self.source_directory))
             .map_err(|err| anyhow!("Failed to create `lib` directory: {}", err))?;
 
         for Template { path, template } in templates.source_templates.iter() {
@@ -388,56 +1517,214 @@ where
             fs::set_permissions(shell.path.clone(), permissions)?;
         }
 
+        for Template { path, template } in templates.extra_files.iter() {
+            if let Some(parent) = Path::new(path).parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|err| anyhow!("Failed to create folder for {}: {}", path, err))?;
+            }
+            fs::write(path, template)
+                .map_err(|err| anyhow!("Failed to write extra file {}: {}", path, err))?;
+        }
+
         Ok(())
     }
 
-    pub fn run(&self) -> anyhow::Result<()> {
-        self.run_command.run().map_err(|err| {
-            anyhow!(
-                "Failed to spawn {} command: {}",
-                self.run_command.command,
-                err
-            )
-        })?;
+    pub fn install(&self) -> anyhow::Result<()> {
+        for command in self.run_commands.iter() {
+            command
+                .run()
+                .map_err(|err| anyhow!("Failed to spawn {} command: {}", command.command, err))?;
+        }
+
+        Ok(())
+    }
 
+    pub fn shell(&self) -> anyhow::Result<()> {
         if let Some(shell) = &self.shell {
-            (shell.get_command)()?.run()?;
+            (shell.get_command)()?.run_with_stdin()?;
         }
 
         Ok(())
     }
 }
 
-pub fn pull_git_repo(project_path: String, repo: &RemoteTarget, shell: bool) -> anyhow::Result<()> {
-    if shell && repo.shell.is_none() {
+/// Build every `Internal` target into the cache, so that later runs can
+/// restore them instead of installing.  Failures are reported per target
+/// rather than stopping the others.
+pub fn warm_cache(config: &Config, opts: &Opts, config_dir: &Path) -> anyhow::Result<()> {
+    // Every target would get the same changes, and most don't have the
+    // dependencies they'd change.
+    if !opts.get_deps().is_empty() || !opts.get_without().is_empty() {
         return Err(anyhow!(
-            "No shell command specified in config for this git repo"
+            "`warm` builds targets as they're configured, so it doesn't take `--dep` or `--without`"
         ));
     }
+    let cwd = env::current_dir()?;
+    let mut names: Vec<&String> = config
+        .targets
+        .iter()
+        .filter(|(_, target)| matches!(target, Target::Internal(_)))
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+
+    let mut results = Vec::new();
+    for name in names {
+        println!("==> Warming {}", name);
+        let started = Instant::now();
+        let result = match config.target(name) {
+            Ok(Target::Internal(target)) => target.warm(opts, config.build_dir.clone(), config_dir),
+            Ok(_) => unreachable!("only Internal targets are warmed"),
+            Err(err) => Err(err),
+        };
+        env::set_current_dir(&cwd)?;
+        if let Err(err) = &result {
+            println!("{}", err);
+        }
+        results.push((name, result.is_ok(), started.elapsed()));
+    }
 
-    ProgramCommand::new(
-        String::from("git"),
-        vec![
-            String::from("clone"),
-            repo.path.clone(),
-            project_path.clone(),
-        ],
-    )
-    .run()
-    .map_err(|err| anyhow!("Failed to clone git repo {}: {}", repo.path.clone(), err))?;
+    println!();
+    let width = results
+        .iter()
+        .map(|(name, _, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, ok, elapsed) in results.iter() {
+        println!(
+            "{:width$}  {:6}  {:.1}s",
+            name,
+            if *ok { "ok" } else { "failed" },
+            elapsed.as_secs_f64(),
+            width = width
+        );
+    }
 
-    env::set_current_dir(project_path)
-        .map_err(|err| anyhow!("Failed to change to project directory: {}", err))?;
+    let failed = results.iter().filter(|(_, ok, _)| !ok).count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} targets failed to warm",
+            failed,
+            results.len()
+        ));
+    }
+    Ok(())
+}
 
-    ProgramCommand::new(repo.build_command.clone(), repo.build_args.clone())
-        .run()
-        .map_err(|err| {
+/// Clone the repo into the project path and build it.  Repos that aren't on
+/// this machine are cloned from a mirror in `build_dir`, fetched into once
+/// it's older than `mirror_ttl`, and the built project is cached by the
+/// commit it was built from.  Offline, a repo that was never mirrored can
+/// still be restored from the target's last cached build.
+pub fn pull_git_repo(
+    name: String,
+    opts: &Opts,
+    repo: &RemoteTarget,
+    build_dir: String,
+    cache: bool,
+    mirror_ttl: Duration,
+) -> anyhow::Result<()> {
+    let shell = opts.get_shell();
+    if shell && repo.shell.is_none() {
+        return Err(anyhow!(
+            "No shell command specified in config for this git repo"
+        ));
+    }
+    let cwd = env::current_dir()?;
+    let project_path = cwd.join(opts.get_path());
+    let build_dir = cwd.join(build_dir);
+
+    let cache = cache && !opts.get_no_cache();
+    let source = if is_local_repo(&repo.path) {
+        Some(repo.path.clone())
+    } else if opts.get_offline() && !mirror::path(&build_dir, &repo.path).is_dir() {
+        // With no mirror there's nothing to resolve `ref` against, but the
+        // target's last build may still be cached.
+        None
+    } else {
+        Some(
+            mirror::update(&build_dir, &repo.path, mirror_ttl, opts.get_offline())
+                .map_err(|err| anyhow!("Failed to mirror git repo {}: {}", repo.path, err))?
+                .to_string_lossy()
+                .to_string(),
+        )
+    };
+    let commit = match &source {
+        Some(source) if cache => {
+            let local = source.strip_prefix("file://").unwrap_or(source);
+            Some(mirror::resolve(Path::new(local), repo.git_ref.as_deref())?)
+        }
+        _ => None,
+    };
+    let hash = match (&source, &commit) {
+        (_, Some(commit)) => Some(repo.hash(commit)),
+        // Only trust the last build if it was built the same way, since
+        // the config may have changed since.
+        (None, None) if cache => fs::read_to_string(build_dir.join(format!("{}.sha1", name)))
+            .ok()
+            .map(|hash| hash.trim().to_string())
+            .filter(|hash| {
+                cache::read_metadata(&build_dir, hash).and_then(|metadata| metadata.settings)
+                    == Some(repo.settings_hash())
+            }),
+        _ => None,
+    };
+
+    if let Some(hash) = hash
+        .as_ref()
+        .filter(|hash| cache::entry_path(&build_dir, hash).is_dir())
+    {
+        println!("Restoring cached build...");
+        fs::create_dir_all(&project_path)
+            .map_err(|err| anyhow!("Failed to create project folder: {}", err))?;
+        cache::restore(&build_dir, hash, &project_path)?;
+        cache::touch(&build_dir, hash)?;
+        env::set_current_dir(&project_path)
+            .map_err(|err| anyhow!("Failed to change to project directory: {}", err))?;
+    } else {
+        let source = source.ok_or_else(|| {
             anyhow!(
-                "Failed to run build command for git repo {}: {}",
-                repo.path,
-                err
+                "Can't clone {} offline, it hasn't been mirrored or built yet",
+                repo.path
             )
         })?;
+        clone_repo(repo, &source, &project_path)
+            .map_err(|err| anyhow!("Failed to clone git repo {}: {}", repo.path.clone(), err))?;
+        repo.git
+            .unwrap_or_default()
+            .apply(&project_path, None, true)?;
+
+        env::set_current_dir(&project_path)
+            .map_err(|err| anyhow!("Failed to change to project directory: {}", err))?;
+
+        ProgramCommand::new(repo.build_command.clone(), repo.build_args.clone())
+            .run()
+            .map_err(|err| {
+                anyhow!(
+                    "Failed to run build command for git repo {}: {}",
+                    repo.path,
+                    err
+                )
+            })?;
+
+        if let (Some(hash), Some(commit)) = (&hash, &commit) {
+            let mut metadata = cache::Metadata::new(
+                name.clone(),
+                String::from("repo"),
+                vec![format!("{}@{}", repo.path, &commit[..12])],
+            );
+            metadata.settings = Some(repo.settings_hash());
+            let stored = cache::store(&project_path, &build_dir, hash)
+                .and_then(|_| cache::write_metadata(&build_dir, hash, &metadata))
+                .and_then(|_| {
+                    fs::write(build_dir.join(format!("{}.sha1", name)), hash)
+                        .map_err(|err| anyhow!("Failed to write hash: {}", err))
+                });
+            if let Err(err) = stored {
+                println!("Failed to cache build: {}", err);
+            }
+        }
+    }
 
     if shell {
         repo.shell
@@ -450,6 +1737,117 @@ pub fn pull_git_repo(project_path: String, repo: &RemoteTarget, shell: bool) ->
     Ok(())
 }
 
+fn is_local_repo(path: &str) -> bool {
+    path.starts_with("file://") || Path::new(path).exists()
+}
+
+/// Puts the repo, at its `ref`, into the project path.  With a `subdir`, the
+/// repo is cloned next to the project path and only that directory is moved
+/// into place.
+fn clone_repo(repo: &RemoteTarget, source: &str, project_path: &Path) -> anyhow::Result<()> {
+    let subdir = match &repo.subdir {
+        Some(subdir) => {
+            let subdir = Path::new(subdir);
+            if !stays_inside(subdir) {
+                return Err(anyhow!(
+                    "subdir must be a relative path inside the repo, got {}",
+                    subdir.display()
+                ));
+            }
+            subdir
+        }
+        None => return fetch_repo(repo, source, project_path),
+    };
+
+    // An existing project folder, like `.`, can't be renamed over, so the
+    // repo is cloned inside it and the subdir's contents moved out instead,
+    // as long as none of them are already there.
+    let existing = project_path.is_dir();
+    let clone_path = if existing {
+        project_path.join(".shelly-cloning")
+    } else {
+        PathBuf::from(format!("{}.cloning", project_path.display()))
+    };
+    if clone_path.exists() {
+        fs::remove_dir_all(&clone_path)?;
+    }
+    let result = fetch_repo(repo, source, &clone_path).and_then(|_| {
+        let source = clone_path.join(subdir);
+        if !source.is_dir() {
+            return Err(anyhow!(
+                "{} is not a directory in the repo",
+                subdir.display()
+            ));
+        }
+        if existing {
+            return directory::move_contents(&source, project_path);
+        }
+        if let Some(parent) = project_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&source, project_path)?;
+        Ok(())
+    });
+    if clone_path.exists() {
+        fs::remove_dir_all(&clone_path)?;
+    }
+    result
+}
+
+/// Clone `source`, which is either the repo itself or a mirror of it, into
+/// `path`.
+fn fetch_repo(repo: &RemoteTarget, source: &str, path: &Path) -> anyhow::Result<()> {
+    // `git -C` below would resolve a relative repo path from inside the new
+    // clone, so local repos are made absolute first.  Git ignores `--depth`
+    // when cloning a plain path, hence `file://` for shallow clones.
+    let url = match fs::canonicalize(source) {
+        Ok(local) if repo.depth.is_some() => format!("file://{}", local.display()),
+        Ok(local) => local.to_string_lossy().to_string(),
+        Err(_) => source.to_string(),
+    };
+    let path = path.to_string_lossy().to_string();
+    let depth = repo.depth.map(|depth| format!("--depth={}", depth));
+    let git = |args: Vec<&str>| {
+        ProgramCommand::new(
+            String::from("git"),
+            args.into_iter().map(String::from).collect(),
+        )
+        .run()
+    };
+
+    match &repo.git_ref {
+        None => {
+            let mut args = vec!["clone"];
+            args.extend(depth.as_deref());
+            args.extend(&[url.as_str(), path.as_str()]);
+            git(args)?;
+        }
+        Some(git_ref) => {
+            // A plain clone can only check out branches and tags, so fetching
+            // the ref on its own also covers commit SHAs, and keeps `depth`
+            // working for all three.
+            git(vec!["init", "--quiet", &path])?;
+            git(vec!["-C", &path, "remote", "add", "origin", &url])?;
+            let mut args = vec!["-C", &path, "fetch", "origin", git_ref.as_str()];
+            args.extend(depth.as_deref());
+            git(args).map_err(|err| anyhow!("Couldn't fetch ref {}: {}", git_ref, err))?;
+            git(vec!["-C", &path, "checkout", "--quiet", "FETCH_HEAD"])?;
+        }
+    }
+
+    // Point a clone of the mirror back at the repo before fetching
+    // submodules, whose URLs can be relative to it.
+    if source != repo.path {
+        git(vec!["-C", &path, "remote", "set-url", "origin", &repo.path])?;
+    }
+    if repo.submodules {
+        let mut args = vec!["-C", &path, "submodule", "update", "--init", "--recursive"];
+        args.extend(depth.as_deref());
+        git(args)?;
+    }
+    Ok(())
+}
+
 pub fn copy_build_directory(
     project_path: String,
     build: &RemoteTarget,
@@ -461,37 +1859,24 @@ pub fn copy_build_directory(
         ));
     }
 
-    fs::create_dir_all(project_path.clone())
-        .map_err(|err| anyhow!("Failed to create project folder: {}", err))?;
-
-    let ignored_dirs = vec!["node_modules"];
-
-    let files: Vec<DirEntry> = WalkDir::new(build.path.clone())
-        .into_iter()
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            let file = entry
-                .path()
-                .strip_prefix(build.path.clone())
-                .unwrap()
-                .to_str()
-                .unwrap();
-            !ignored_dirs.iter().any(|dir| file.starts_with(dir))
-        })
-        .collect();
-
-    for file in files {
-        println!("file path: {:?}", file.path());
-        let folder_path = file.path().strip_prefix(build.path.clone())?;
-        let path_to_file = PathBuf::from(project_path.clone()).join(folder_path);
-        if file.metadata()?.is_dir() {
-            println!("Creating folder {:?}", file.path());
-            fs::create_dir_all(file.path())?;
-        } else {
-            println!("Writing {:?} to {:?}", file.path(), path_to_file);
-            fs::copy(file.path(), path_to_file)?;
-        }
-    }
+    let source = fs::canonicalize(&build.path)
+        .map_err(|err| anyhow!("Failed to read build folder {}: {}", build.path, err))?;
+    let exclude = build.exclude.clone().unwrap_or_else(|| {
+        directory::DEFAULT_EXCLUDE
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect()
+    });
+    let filter = directory::Filter::new(&source, &exclude, &build.include)?;
+    let files = directory::entries(&source, filter, build.symlinks.unwrap_or_default())?;
+    let project_path = env::current_dir()?.join(project_path);
+    directory::copy(&source, &files, &project_path)
+        .map_err(|err| anyhow!("Failed to copy build folder {}: {}", build.path, err))?;
+
+    build
+        .git
+        .unwrap_or_default()
+        .apply(&project_path, None, false)?;
 
     env::set_current_dir(project_path)
         .map_err(|err| anyhow!("Failed to change to project directory: {}", err))?;
@@ -517,3 +1902,404 @@ pub fn copy_build_directory(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::temp_dir;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=shelly",
+                "-c",
+                "user.email=shelly@example.com",
+                "-c",
+                "init.defaultBranch=main",
+                "-c",
+                "protocol.file.allow=always",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// A bare repo whose `main` has two commits, with `v1` tagging the first
+    /// and `templates/node` added in the second.
+    fn fixture(name: &str) -> (PathBuf, String) {
+        let root = temp_dir(name);
+        let work = root.join("work");
+        fs::create_dir_all(&work).unwrap();
+        git(&work, &["init", "--quiet"]);
+        fs::write(work.join("README"), "first").unwrap();
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "--quiet", "-m", "first"]);
+        git(&work, &["tag", "v1"]);
+        let first = git(&work, &["rev-parse", "HEAD"]);
+        fs::write(work.join("README"), "second").unwrap();
+        fs::create_dir_all(work.join("templates/node")).unwrap();
+        fs::write(work.join("templates/node/package.json"), "{}").unwrap();
+        git(&work, &["add", "."]);
+        git(&work, &["commit", "--quiet", "-m", "second"]);
+        git(&root, &["clone", "--quiet", "--bare", "work", "repo.git"]);
+        (root, first)
+    }
+
+    fn remote(root: &Path) -> RemoteTarget {
+        RemoteTarget::new(
+            root.join("repo.git").to_string_lossy().to_string(),
+            String::from("true"),
+            vec![],
+            None,
+        )
+    }
+
+    fn readme(project: &Path) -> String {
+        fs::read_to_string(project.join("README")).unwrap()
+    }
+
+    #[test]
+    fn only_paths_inside_stay_inside() {
+        assert!(stays_inside(Path::new("src/index.js")));
+        assert!(stays_inside(Path::new(".env")));
+        assert!(!stays_inside(Path::new("../x")));
+        assert!(!stays_inside(Path::new("src/../../x")));
+        assert!(!stays_inside(Path::new("/etc/x")));
+        assert!(!stays_inside(Path::new("./x")));
+        assert!(!stays_inside(Path::new("")));
+    }
+
+    /// A target whose install copies its build file into `deps/`, or fails
+    /// without `installs`.
+    fn scratch_target(installs: bool) -> LanguageTarget<HashMap<String, String>> {
+        let install = if installs {
+            "mkdir -p deps && cp deps.txt deps/installed && pwd -P > deps/path"
+        } else {
+            "false"
+        };
+        let mut target = LanguageTarget::new(
+            (String::from("deps.txt"), String::from("deps")),
+            HashMap::new(),
+            String::from("SCRATCH"),
+            vec![ProgramCommand::new(
+                String::from("sh"),
+                vec![String::from("-c"), String::from(install)],
+            )],
+            None,
+            String::from("src"),
+            vec![(String::from("main.txt"), String::from("main"))],
+        );
+        target.add_extra_files(vec![(String::from("extra.txt"), String::from("extra"))]);
+        target.set_relocate(vec![String::from("deps")]);
+        target
+    }
+
+    #[test]
+    fn caches_only_the_build() {
+        let root = temp_dir("build-cache");
+        let build_dir = root.join("build");
+        let project = root.join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(project.join("unrelated.txt"), "").unwrap();
+        let build = |target: LanguageTarget<HashMap<String, String>>, project: &Path| {
+            let cwd = env::current_dir().unwrap();
+            let built = target.build(
+                String::from("scratch"),
+                project.to_string_lossy().to_string(),
+                build_dir.to_string_lossy().to_string(),
+                true,
+            );
+            env::set_current_dir(cwd).unwrap();
+            built
+        };
+
+        build(scratch_target(true), &project).unwrap();
+        assert!(project.join("deps/installed").is_file());
+        assert!(project.join("unrelated.txt").is_file());
+        let path = |project: &Path| fs::read_to_string(project.join("deps/path")).unwrap();
+        assert_eq!(
+            path(&project).trim(),
+            project.canonicalize().unwrap().to_string_lossy()
+        );
+        let entry = fs::read_dir(&build_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_dir())
+            .unwrap();
+        assert!(entry.join("deps/installed").is_file());
+        assert_eq!(path(&entry).trim(), cache::PROJECT_PATH);
+        for path in ["unrelated.txt", "src/main.txt", "extra.txt"] {
+            assert!(!entry.join(path).exists(), "{} was cached", path);
+        }
+
+        // Restored rather than installed again, which would fail.
+        let other = root.join("other");
+        build(scratch_target(false), &other).unwrap();
+        assert!(other.join("deps/installed").is_file());
+        assert_eq!(
+            path(&other).trim(),
+            other.canonicalize().unwrap().to_string_lossy()
+        );
+        assert_eq!(
+            fs::read_to_string(other.join("src/main.txt")).unwrap(),
+            "main"
+        );
+        assert!(other.join("extra.txt").is_file());
+        assert!(!other.join("unrelated.txt").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn merges_deps_by_normalized_name() {
+        let normalize = |name: &str| SupportedLanguage::node.normalize_name(name);
+        let deps = vec![
+            Dependency::new("JSONStream", "1.3"),
+            Dependency::new("axios", "0.20"),
+        ];
+
+        let merged = merge_deps(deps.clone(), vec![], vec![], normalize).unwrap();
+        let names: Vec<&str> = merged.iter().map(|dep| dep.name.as_str()).collect();
+        assert_eq!(names, vec!["axios", "JSONStream"]);
+
+        let merged = merge_deps(deps.clone(), vec![], vec!["jsonstream".into()], normalize);
+        assert_eq!(merged.unwrap().len(), 1);
+
+        let mut twice = deps.clone();
+        twice.push(Dependency::new("jsonStream", "1.3"));
+        assert!(merge_deps(twice, vec![], vec![], normalize).is_err());
+
+        let renamed = vec![
+            Dependency::new("jsonstream", "1.3"),
+            Dependency::new("axios", "0.20"),
+        ];
+        assert_eq!(
+            generate_hash(merge_deps(deps, vec![], vec![], normalize).unwrap(), "node"),
+            generate_hash(
+                merge_deps(renamed, vec![], vec![], normalize).unwrap(),
+                "node"
+            )
+        );
+
+        // The key builds are cached by, with the build file rendered.
+        let key = |name: &'static str| {
+            let normalize = |name: &str| SupportedLanguage::python.normalize_name(name);
+            let deps = vec![Dependency::new(name, "2.25")];
+            let deps = merge_deps(deps, vec![], vec![], normalize).unwrap();
+            let templates = TemplateDirs::new(None, env::temp_dir().join("shelly-no-templates"));
+            let mut target = python::new(deps.clone(), false, &templates).unwrap();
+            target.describe(String::from("python"), &deps);
+            let rendered = target.generate_templates().unwrap();
+            assert!(rendered.build_template.template.contains(name));
+            target.hash_with_build_file(&rendered)
+        };
+        assert_eq!(key("requests"), key("Requests"));
+        assert_eq!(key("requests"), key("REQUESTS"));
+    }
+
+    #[test]
+    fn includes_dep_sets_by_normalized_name() {
+        let normalize = |name: &str| SupportedLanguage::node.normalize_name(name);
+        let mut sets = HashMap::new();
+        sets.insert(
+            String::from("web"),
+            vec![
+                Dependency::new("Axios", "0.20"),
+                Dependency::new("cheerio", "1.0"),
+            ],
+        );
+        let own = vec![Dependency::new("axios", "0.21")];
+
+        let deps = include_deps(&sets, &[String::from("web")], own, normalize).unwrap();
+        assert_eq!(deps.len(), 2);
+        assert_eq!(deps[0].name, "axios");
+        assert_eq!(deps[0].version.as_deref(), Some("0.21"));
+        assert!(merge_deps(deps, vec![], vec![], normalize).is_ok());
+
+        assert!(include_deps(&sets, &[String::from("cli")], vec![], normalize).is_err());
+    }
+
+    #[test]
+    fn clones_default_branch() {
+        let (root, _) = fixture("default");
+        let project = root.join("project");
+        clone_repo(&remote(&root), &remote(&root).path, &project).unwrap();
+        assert_eq!(readme(&project), "second");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_tag_and_commit() {
+        let (root, first) = fixture("refs");
+        let mut repo = remote(&root);
+        repo.git_ref = Some(String::from("v1"));
+        clone_repo(&repo, &repo.path, &root.join("tag")).unwrap();
+        assert_eq!(readme(&root.join("tag")), "first");
+
+        repo.git_ref = Some(first.clone());
+        clone_repo(&repo, &repo.path, &root.join("sha")).unwrap();
+        assert_eq!(readme(&root.join("sha")), "first");
+        assert_eq!(git(&root.join("sha"), &["rev-parse", "HEAD"]), first);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_shallow() {
+        let (root, _) = fixture("shallow");
+        let mut repo = remote(&root);
+        repo.depth = Some(1);
+        let project = root.join("project");
+        clone_repo(&repo, &repo.path, &project).unwrap();
+        assert_eq!(git(&project, &["rev-list", "--count", "HEAD"]), "1");
+
+        repo.git_ref = Some(String::from("main"));
+        let pinned = root.join("pinned");
+        clone_repo(&repo, &repo.path, &pinned).unwrap();
+        assert_eq!(git(&pinned, &["rev-list", "--count", "HEAD"]), "1");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_from_mirror() {
+        let (root, first) = fixture("mirror");
+        let mut repo = remote(&root);
+        let mirror = mirror::update(
+            &root.join("build"),
+            &repo.path,
+            Duration::from_secs(60),
+            false,
+        )
+        .unwrap();
+        assert_eq!(mirror::resolve(&mirror, Some("v1")).unwrap(), first);
+
+        repo.git_ref = Some(String::from("v1"));
+        let project = root.join("project");
+        clone_repo(&repo, &mirror.to_string_lossy(), &project).unwrap();
+        assert_eq!(readme(&project), "first");
+        assert_eq!(git(&project, &["config", "remote.origin.url"]), repo.path);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn only_replaces_cloned_repositories() {
+        let (root, _) = fixture("git-mode");
+        let project = root.join("project");
+        clone_repo(&remote(&root), &remote(&root).path, &project).unwrap();
+        let first = git(&project, &["rev-parse", "HEAD"]);
+
+        assert!(GitMode::fresh.apply(&project, None, false).is_err());
+        assert!(GitMode::none.apply(&project, None, false).is_err());
+        assert_eq!(git(&project, &["rev-parse", "HEAD"]), first);
+
+        GitMode::fresh.apply(&project, None, true).unwrap();
+        assert_eq!(git(&project, &["rev-list", "--count", "HEAD"]), "1");
+        assert_ne!(git(&project, &["rev-parse", "HEAD"]), first);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn fetches_into_stale_mirrors() {
+        let (root, _) = fixture("mirror-ttl");
+        let url = remote(&root).path;
+        let build_dir = root.join("build");
+        let hour = Duration::from_secs(60 * 60);
+        let mirror = mirror::update(&build_dir, &url, hour, false).unwrap();
+        let head = mirror::resolve(&mirror, None).unwrap();
+
+        let work = root.join("work");
+        git(
+            &work,
+            &["commit", "--quiet", "--allow-empty", "-m", "third"],
+        );
+        git(&work, &["push", "--quiet", &url, "main"]);
+        mirror::update(&build_dir, &url, hour, false).unwrap();
+        assert_eq!(mirror::resolve(&mirror, None).unwrap(), head);
+        std::thread::sleep(Duration::from_millis(10));
+        mirror::update(&build_dir, &url, Duration::ZERO, true).unwrap();
+        assert_eq!(mirror::resolve(&mirror, None).unwrap(), head);
+        mirror::update(&build_dir, &url, Duration::ZERO, false).unwrap();
+        assert_eq!(
+            mirror::resolve(&mirror, None).unwrap(),
+            git(&work, &["rev-parse", "HEAD"])
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn restores_cached_builds_offline_without_a_mirror() {
+        use clap::Clap;
+
+        let (root, _) = fixture("offline");
+        let build_dir = root.join("build");
+        let mut repo = remote(&root);
+        repo.path = String::from("https://example.invalid/shelly/repo.git");
+        let built = root.join("built");
+        fs::create_dir(&built).unwrap();
+        fs::write(built.join("README"), "built").unwrap();
+        let hash = repo.hash("0123456789abcdef");
+        cache::store(&built, &build_dir, &hash).unwrap();
+        let mut metadata = cache::Metadata::new(String::from("repo"), String::from("repo"), vec![]);
+        metadata.settings = Some(repo.settings_hash());
+        cache::write_metadata(&build_dir, &hash, &metadata).unwrap();
+        fs::write(build_dir.join("repo.sha1"), &hash).unwrap();
+
+        let project = root.join("project");
+        let opts = Opts::parse_from(["shelly", "--offline", &project.to_string_lossy()]);
+        let pull = |name: &str, repo: &RemoteTarget| {
+            let cwd = env::current_dir().unwrap();
+            let pulled = pull_git_repo(
+                name.to_string(),
+                &opts,
+                repo,
+                build_dir.to_string_lossy().to_string(),
+                true,
+                Duration::from_secs(60 * 60),
+            );
+            env::set_current_dir(cwd).unwrap();
+            pulled
+        };
+        pull("repo", &repo).unwrap();
+        assert_eq!(readme(&project), "built");
+        assert!(pull("other", &repo).is_err());
+
+        // Built some other way than the config now asks for.
+        fs::remove_dir_all(&project).unwrap();
+        let mut changed = repo.clone();
+        changed.git_ref = Some(String::from("v1"));
+        let err = pull("repo", &changed).unwrap_err();
+        assert!(err.to_string().contains("hasn't been mirrored"), "{}", err);
+        assert!(!project.exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_subdir() {
+        let (root, _) = fixture("subdir");
+        let mut repo = remote(&root);
+        repo.subdir = Some(String::from("templates/node"));
+        let project = root.join("project");
+        clone_repo(&repo, &repo.path, &project).unwrap();
+        assert!(project.join("package.json").exists());
+        assert!(!project.join("README").exists());
+        assert!(!root.join("project.cloning").exists());
+
+        // A project path that already exists, as `.` always does.
+        let existing = root.join("existing");
+        fs::create_dir(&existing).unwrap();
+        clone_repo(&repo, &repo.path, &existing.join(".")).unwrap();
+        assert!(existing.join("package.json").exists());
+        assert!(!existing.join(".shelly-cloning").exists());
+        assert!(clone_repo(&repo, &repo.path, &existing.join(".")).is_err());
+
+        repo.subdir = Some(String::from("../work"));
+        assert!(clone_repo(&repo, &repo.path, &root.join("outside")).is_err());
+        repo.subdir = Some(String::from("missing"));
+        assert!(clone_repo(&repo, &repo.path, &root.join("missing")).is_err());
+        assert!(!root.join("missing").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::target::SupportedLanguage;
+use crate::{config, elixir, node, python, ruby, rust};
+
+/// The version of the built-in templates, part of every build's hash.  Bump
+/// it when they change in a way that makes builds cached from the old ones
+/// wrong.
+pub const VERSION: u32 = 3;
+
+/// A language's built-in templates, by the file name they can be overridden
+/// with.
+pub type BuiltinTemplates<const N: usize> = [(&'static str, &'static str); N];
+
+/// Values for the variables a language's built-in templates use, for when
+/// neither the target nor the command line set them.
+pub fn vars(defaults: &[(&str, &str)]) -> HashMap<String, String> {
+    defaults
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Where to look for templates before falling back to the ones built into
+/// shelly, in order of precedence.
+pub struct TemplateDirs {
+    dirs: Vec<PathBuf>,
+}
+
+impl TemplateDirs {
+    pub fn new(target_dir: Option<PathBuf>, user_dir: PathBuf) -> TemplateDirs {
+        TemplateDirs {
+            dirs: target_dir.into_iter().chain(Some(user_dir)).collect(),
+        }
+    }
+
+    /// Read `file_name` from the first directory that has it, otherwise use
+    /// the built-in `default`.
+    pub fn load(&self, file_name: &str, default: &str) -> anyhow::Result<String> {
+        for dir in self.dirs.iter() {
+            let path = dir.join(file_name);
+            if path.is_file() {
+                return fs::read_to_string(&path)
+                    .map_err(|err| anyhow!("Failed to read template {:?}: {}", path, err));
+            }
+        }
+
+        Ok(default.to_string())
+    }
+}
+
+/// How to ask for a template variable when it hasn't been set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Prompt {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+/// The names of the variables a template refers to, as `vars.<name>`.  Only
+/// the `{ ... }` values and `{{ ... }}` blocks that TinyTemplate evaluates
+/// are looked at, not the literal text around them or escaped `\{`.
+pub fn referenced_vars(template: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        if rest[..start].ends_with('\\') {
+            rest = &rest[start + 1..];
+            continue;
+        }
+        let (open, close) = if rest[start..].starts_with("{{") {
+            ("{{", "}}")
+        } else {
+            ("{", "}")
+        };
+        let body = &rest[start + open.len()..];
+        let end = match body.find(close) {
+            Some(end) => end,
+            None => break,
+        };
+        collect_vars(&body[..end], &mut names);
+        rest = &body[end + close.len()..];
+    }
+
+    names
+}
+
+/// Add the variables `expression` uses as `vars.<name>` or
+/// `@root.vars.<name>` to `names`.
+fn collect_vars(expression: &str, names: &mut Vec<String>) {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    for (index, _) in expression.match_indices("vars.") {
+        let before = &expression[..index];
+        let is_path_start = before.ends_with("@root.")
+            || !before
+                .chars()
+                .next_back()
+                .is_some_and(|c| is_name(c) || c == '.' || c == '@');
+        if !is_path_start {
+            continue;
+        }
+        let rest = &expression[index + "vars.".len()..];
+        let name: String = rest.chars().take_while(|c| is_name(*c)).collect();
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+}
+
+/// Ask on the terminal for the value of `name`.  When not `interactive`,
+/// the prompt's default is used instead, and it is an error to have none.
+pub fn prompt(name: &str, prompt: Option<&Prompt>, interactive: bool) -> anyhow::Result<String> {
+    let prompt = prompt.cloned().unwrap_or_default();
+
+    if !interactive {
+        return prompt.default.ok_or_else(|| {
+            anyhow!(
+                "Template variable `{}` is not set; pass it with `--var {}=<value>`",
+                name,
+                name
+            )
+        });
+    }
+
+    let stdin = io::stdin();
+    loop {
+        if let Some(description) = &prompt.description {
+            println!("{}", description);
+        }
+        match &prompt.default {
+            Some(default) => print!("{} [{}]: ", name, default),
+            None => print!("{}: ", name),
+        }
+        io::stdout().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(anyhow!("No value given for template variable `{}`", name));
+        }
+
+        match (line.trim(), &prompt.default) {
+            ("", Some(default)) => return Ok(default.clone()),
+            ("", None) => continue,
+            (value, _) => return Ok(value.to_string()),
+        }
+    }
+}
+
+/// The user's template directory for `language`, in shelly's own config
+/// folder whichever `shelly.yml` is used.
+pub fn user_dir(language: &SupportedLanguage) -> anyhow::Result<PathBuf> {
+    Ok(config::home_dir()?
+        .join("templates")
+        .join(language.to_string()))
+}
+
+fn builtin(language: &SupportedLanguage) -> &'static [(&'static str, &'static str)] {
+    match language {
+        SupportedLanguage::elixir => &elixir::TEMPLATES,
+        SupportedLanguage::node => &node::TEMPLATES,
+        SupportedLanguage::python => &python::TEMPLATES,
+        SupportedLanguage::ruby => &ruby::TEMPLATES,
+        SupportedLanguage::rust => &rust::TEMPLATES,
+    }
+}
+
+/// Copy the built-in templates for `language` into the user's template
+/// directory, where they take precedence over the built-in ones.
+pub fn eject(language: &SupportedLanguage, force: bool) -> anyhow::Result<()> {
+    let dir = user_dir(language)?;
+    fs::create_dir_all(&dir)
+        .map_err(|err| anyhow!("Failed to create template directory: {}", err))?;
+
+    for (file_name, template) in builtin(language).iter() {
+        let path = dir.join(file_name);
+        if path.exists() && !force {
+            println!("Skipping {:?}, it already exists", path);
+            continue;
+        }
+        fs::write(&path, template)
+            .map_err(|err| anyhow!("Failed to write template {:?}: {}", path, err))?;
+        println!("Wrote {:?}", path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_vars_in_values_and_blocks() {
+        let template = "{ vars.name } { vars.name | upper }\n\
+            {{ if vars.flag }}{ @root.vars.root }{{ endif }}";
+        assert_eq!(referenced_vars(template), vec!["name", "flag", "root"]);
+    }
+
+    #[test]
+    fn ignores_vars_outside_expressions() {
+        let template = "const count = obj.vars.count;\n\
+            \\{ vars.escaped }\n\
+            { obj.vars.field } {{ for item in items }}{ item }{{ endfor }}";
+        assert!(referenced_vars(template).is_empty());
+    }
+
+    #[test]
+    fn ignores_unclosed_expressions() {
+        assert!(referenced_vars("{ vars.open").is_empty());
+    }
+}
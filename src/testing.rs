@@ -0,0 +1,14 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// A fresh, empty folder for the test fixture called `name`, which only
+/// this test run uses.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("shelly-{}-{}", name, std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A version as written in `shelly.yml`, like `1`, `1.2`, `1.0.0-rc.3` or
+/// `1.0.0+build.5`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub numbers: Vec<u64>,
+    pub pre: Option<String>,
+    pub build: Option<String>,
+}
+
+impl Version {
+    /// The version with missing minor and patch numbers filled in as zeros,
+    /// for languages that only compare full versions.
+    pub fn padded(&self) -> Version {
+        let mut numbers = self.numbers.clone();
+        numbers.resize(3.max(numbers.len()), 0);
+        Version {
+            numbers,
+            ..self.clone()
+        }
+    }
+
+    /// The version with one more number than it was written with, so that
+    /// `~>` and `~=` only allow the last number given to change.
+    pub fn extended(&self) -> Version {
+        let mut numbers = self.numbers.clone();
+        if numbers.len() < 3 {
+            numbers.push(0);
+        }
+        Version {
+            numbers,
+            ..self.clone()
+        }
+    }
+
+    fn bump(&self, index: usize) -> Version {
+        let mut numbers = self.padded().numbers;
+        numbers[index] += 1;
+        for number in numbers.iter_mut().skip(index + 1) {
+            *number = 0;
+        }
+        Version {
+            numbers,
+            pre: None,
+            build: None,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let numbers = self
+            .numbers
+            .iter()
+            .map(|number| number.to_string())
+            .collect::<Vec<String>>()
+            .join(".");
+        write!(f, "{}", numbers)?;
+        if let Some(pre) = &self.pre {
+            write!(f, "-{}", pre)?;
+        }
+        if let Some(build) = &self.build {
+            write!(f, "+{}", build)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Version, String> {
+        // Build metadata comes last, and may itself contain `-`.
+        let (rest, build) = match s.split_once('+') {
+            Some((rest, build)) => (rest, Some(build.to_string())),
+            None => (s, None),
+        };
+        let (numbers, pre) = match rest.split_once('-') {
+            Some((numbers, pre)) => (numbers, Some(pre.to_string())),
+            None => (rest, None),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|number| number.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|_| format!("{} is not a version", s))?;
+        if numbers.len() > 3 {
+            return Err(format!("{} is not a version", s));
+        }
+        Ok(Version {
+            numbers,
+            pre,
+            build,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    /// The operator as written by most languages.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Greater => ">",
+            Operator::GreaterEq => ">=",
+            Operator::Less => "<",
+            Operator::LessEq => "<=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+        }
+    }
+}
+
+pub type Comparator = (Operator, Version);
+
+/// What versions of a dependency are acceptable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Requirement {
+    /// A plain version, like `1.2`, meaning whatever the language usually
+    /// considers compatible with it.
+    Compatible(Version),
+    /// `=1.2.3`
+    Exact(Version),
+    /// `^1.2.3`, up to the next breaking version.
+    Caret(Version),
+    /// `~1.2.3`, up to the next minor version, or major if none is given.
+    Tilde(Version),
+    /// Comparisons that all have to hold, like `>= 1.0, < 2.0`.
+    Range(Vec<Comparator>),
+    /// `*` or `latest`.
+    Any,
+}
+
+impl Requirement {
+    /// The requirement written as comparisons, for languages without an
+    /// operator for it.
+    pub fn comparators(&self) -> Vec<Comparator> {
+        match self {
+            Requirement::Compatible(version) | Requirement::Caret(version) => {
+                let index = version
+                    .numbers
+                    .iter()
+                    .position(|number| *number != 0)
+                    .unwrap_or(version.numbers.len() - 1);
+                vec![
+                    (Operator::GreaterEq, version.padded()),
+                    (Operator::Less, version.bump(index)),
+                ]
+            }
+            Requirement::Tilde(version) => {
+                let index = if version.numbers.len() > 1 { 1 } else { 0 };
+                vec![
+                    (Operator::GreaterEq, version.padded()),
+                    (Operator::Less, version.bump(index)),
+                ]
+            }
+            Requirement::Exact(version) => vec![(Operator::Equal, version.clone())],
+            Requirement::Range(comparators) => comparators.clone(),
+            Requirement::Any => vec![(Operator::GreaterEq, Version::from_str("0.0.0").unwrap())],
+        }
+    }
+}
+
+impl FromStr for Requirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Requirement, String> {
+        let s = s.trim();
+        if s.is_empty() || s == "*" || s == "latest" {
+            return Ok(Requirement::Any);
+        }
+        if s.contains("||") {
+            return Err(format!(
+                "{} has alternatives, use `requirement` to write it as-is",
+                s
+            ));
+        }
+
+        let comparators = parse_comparators(s)?;
+        match comparators.as_slice() {
+            [(None, version)] => Ok(Requirement::Compatible(version.clone())),
+            [(Some(Prefix::Caret), version)] => Ok(Requirement::Caret(version.clone())),
+            [(Some(Prefix::Tilde), version)] => Ok(Requirement::Tilde(version.clone())),
+            [(Some(Prefix::Operator(Operator::Equal)), version)] => {
+                Ok(Requirement::Exact(version.clone()))
+            }
+            _ => comparators
+                .into_iter()
+                .map(|(prefix, version)| match prefix {
+                    Some(Prefix::Operator(operator)) => Ok((operator, version)),
+                    _ => Err(format!(
+                        "{} mixes a range with a plain, `^` or `~` version",
+                        s
+                    )),
+                })
+                .collect::<Result<Vec<Comparator>, String>>()
+                .map(Requirement::Range),
+        }
+    }
+}
+
+enum Prefix {
+    Caret,
+    Tilde,
+    Operator(Operator),
+}
+
+fn parse_comparators(s: &str) -> Result<Vec<(Option<Prefix>, Version)>, String> {
+    let mut comparators = Vec::new();
+    let mut rest = s;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if let Some(stripped) = rest.strip_prefix("and ") {
+            rest = stripped;
+            continue;
+        }
+        if rest.is_empty() {
+            break;
+        }
+        // Elixir and ruby disagree with `~` about what `~> 1.2` allows.
+        if rest.starts_with("~>") {
+            return Err(format!(
+                "{} uses `~>`, use `~` or `^` instead, or `requirement` to write it as-is",
+                s
+            ));
+        }
+
+        let prefixes = [
+            (">=", Some(Prefix::Operator(Operator::GreaterEq))),
+            ("<=", Some(Prefix::Operator(Operator::LessEq))),
+            ("==", Some(Prefix::Operator(Operator::Equal))),
+            ("!=", Some(Prefix::Operator(Operator::NotEqual))),
+            (">", Some(Prefix::Operator(Operator::Greater))),
+            ("<", Some(Prefix::Operator(Operator::Less))),
+            ("=", Some(Prefix::Operator(Operator::Equal))),
+            ("^", Some(Prefix::Caret)),
+            ("~", Some(Prefix::Tilde)),
+        ];
+        let mut prefix = None;
+        for (symbol, candidate) in prefixes {
+            if let Some(stripped) = rest.strip_prefix(symbol) {
+                prefix = candidate;
+                rest = stripped.trim_start();
+                break;
+            }
+        }
+
+        let end = rest
+            .find(|c: char| c.is_whitespace() || c == ',')
+            .unwrap_or(rest.len());
+        comparators.push((prefix, Version::from_str(&rest[..end])?));
+        rest = &rest[end..];
+    }
+
+    Ok(comparators)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{elixir, node, python, ruby, rust};
+
+    fn version(s: &str) -> Version {
+        Version::from_str(s).unwrap()
+    }
+
+    fn requirement(s: &str) -> Requirement {
+        Requirement::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!(version("1.2").numbers, vec![1, 2]);
+        let rc = version("1.0.0-rc.3");
+        assert_eq!(rc.pre.as_deref(), Some("rc.3"));
+        assert_eq!(rc.build, None);
+        let build = version("1.0.0-beta-2+build.5-x");
+        assert_eq!(build.pre.as_deref(), Some("beta-2"));
+        assert_eq!(build.build.as_deref(), Some("build.5-x"));
+        assert_eq!(build.to_string(), "1.0.0-beta-2+build.5-x");
+        assert_eq!(version("1.0.0+20130313").to_string(), "1.0.0+20130313");
+        assert!(Version::from_str("1.2.3.4").is_err());
+        assert!(Version::from_str("one").is_err());
+    }
+
+    #[test]
+    fn parses_requirements() {
+        assert_eq!(requirement("1.2"), Requirement::Compatible(version("1.2")));
+        assert_eq!(requirement("=1.2.3"), Requirement::Exact(version("1.2.3")));
+        assert_eq!(requirement("^1.2"), Requirement::Caret(version("1.2")));
+        assert_eq!(requirement("~1.2"), Requirement::Tilde(version("1.2")));
+        assert_eq!(requirement("*"), Requirement::Any);
+        assert_eq!(
+            requirement(">= 1.0, < 2.0"),
+            Requirement::Range(vec![
+                (Operator::GreaterEq, version("1.0")),
+                (Operator::Less, version("2.0")),
+            ])
+        );
+        assert!(Requirement::from_str("~> 1.2").is_err());
+        assert!(Requirement::from_str("1 || 2").is_err());
+        assert!(Requirement::from_str("^1, < 2").is_err());
+    }
+
+    #[test]
+    fn pads_extends_and_bumps() {
+        assert_eq!(version("1").padded().to_string(), "1.0.0");
+        assert_eq!(version("1.2+b").padded().to_string(), "1.2.0+b");
+        assert_eq!(version("1").extended().to_string(), "1.0");
+        assert_eq!(version("1.2.3").extended().to_string(), "1.2.3");
+        assert_eq!(version("1.2.3-rc.1+b").bump(1).to_string(), "1.3.0");
+        assert_eq!(
+            requirement("^0.2.3").comparators(),
+            vec![
+                (Operator::GreaterEq, version("0.2.3")),
+                (Operator::Less, version("0.3.0")),
+            ]
+        );
+        assert_eq!(
+            requirement("~1").comparators(),
+            vec![
+                (Operator::GreaterEq, version("1.0.0")),
+                (Operator::Less, version("2.0.0")),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_exact_versions_in_full() {
+        let exact = requirement("=1.2");
+        assert_eq!(elixir::generate_requirement(&exact).unwrap(), "== 1.2.0");
+        assert_eq!(node::generate_requirement(&exact).unwrap(), "1.2.0");
+        assert_eq!(rust::generate_requirement(&exact).unwrap(), "=1.2.0");
+        assert_eq!(python::generate_requirement(&exact).unwrap(), "==1.2");
+        assert_eq!(ruby::generate_requirement(&exact).unwrap(), "\"= 1.2\"");
+    }
+
+    #[test]
+    fn renders_each_language() {
+        let cases = [
+            ("1.2", ["~> 1.2", "^1.2", "1.2", "~=1.2", "\"~> 1.2\""]),
+            ("1", ["~> 1.0", "^1", "1", "~=1.0", "\"~> 1\""]),
+            (
+                "~1.2",
+                ["~> 1.2.0", "~1.2", "~1.2", "~=1.2.0", "\"~> 1.2.0\""],
+            ),
+            (
+                "^1.2",
+                [
+                    ">= 1.2.0 and < 2.0.0",
+                    "^1.2",
+                    "^1.2",
+                    ">=1.2.0,<2.0.0",
+                    "\">= 1.2.0\", \"< 2.0.0\"",
+                ],
+            ),
+            (
+                ">= 1.0, < 2.0",
+                [
+                    ">= 1.0.0 and < 2.0.0",
+                    ">=1.0 <2.0",
+                    ">=1.0, <2.0",
+                    ">=1.0,<2.0",
+                    "\">= 1.0\", \"< 2.0\"",
+                ],
+            ),
+            ("*", [">= 0.0.0", "*", "*", "", "\">= 0\""]),
+            (
+                "=1.0.0-rc.1+b",
+                [
+                    "== 1.0.0-rc.1+b",
+                    "1.0.0-rc.1+b",
+                    "=1.0.0-rc.1+b",
+                    "==1.0.0-rc.1+b",
+                    "\"= 1.0.0-rc.1+b\"",
+                ],
+            ),
+        ];
+        for (written, [elixir, node, rust, python, ruby]) in cases.iter() {
+            let requirement = requirement(written);
+            assert_eq!(elixir::generate_requirement(&requirement).unwrap(), *elixir);
+            assert_eq!(node::generate_requirement(&requirement).unwrap(), *node);
+            assert_eq!(rust::generate_requirement(&requirement).unwrap(), *rust);
+            assert_eq!(python::generate_requirement(&requirement).unwrap(), *python);
+            assert_eq!(ruby::generate_requirement(&requirement).unwrap(), *ruby);
+        }
+    }
+}